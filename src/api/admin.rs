@@ -0,0 +1,75 @@
+use actix_web::{delete, get, post, web, HttpRequest, HttpResponse};
+use log::info;
+use serde::Serialize;
+
+use crate::apierror::ApiError;
+use crate::auth::{self, Auth, Role};
+use crate::renderer::Renderer;
+
+#[derive(Serialize)]
+struct PreviewLink {
+    url: String,
+}
+
+#[derive(Serialize)]
+struct ReloadSummary {
+    posts: usize,
+    redirects: usize,
+}
+
+/// Exchanges a bearer token for a session cookie, so browsers can see drafts.
+#[post("/session")]
+pub async fn create_session(
+    req: HttpRequest,
+    auth: web::Data<Auth>,
+) -> Result<HttpResponse, ApiError> {
+    let identity = auth::require(&req, Role::Reader)?;
+    let cookie = auth.session_cookie(&identity)?;
+    info!(
+        "Started a session for {} ({})",
+        identity.name, identity.role
+    );
+
+    Ok(HttpResponse::Ok().cookie(cookie).json(identity))
+}
+
+#[get("/session")]
+pub async fn get_session(req: HttpRequest) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json(auth::require(&req, Role::Reader)?))
+}
+
+#[delete("/session")]
+pub async fn delete_session(auth: web::Data<Auth>) -> HttpResponse {
+    HttpResponse::NoContent()
+        .cookie(auth.removal_cookie())
+        .finish()
+}
+
+/// Signed link to an unpublished post, for people without an account.
+#[get("/preview/{slug:.*}")]
+pub async fn preview_link(
+    req: HttpRequest,
+    slug: web::Path<String>,
+    renderer: web::Data<Renderer>,
+) -> Result<HttpResponse, ApiError> {
+    auth::require(&req, Role::Author)?;
+    let url = renderer.preview_url(&slug)?;
+
+    Ok(HttpResponse::Ok().json(PreviewLink { url }))
+}
+
+/// Rebuilds the post index, e.g. after files were changed on disk.
+#[post("/admin/reload")]
+pub async fn reload(
+    req: HttpRequest,
+    renderer: web::Data<Renderer>,
+) -> Result<HttpResponse, ApiError> {
+    let identity = auth::require(&req, Role::Admin)?;
+    renderer.reload_posts()?;
+    info!("{} reloaded the post index", identity.name);
+
+    Ok(HttpResponse::Ok().json(ReloadSummary {
+        posts: renderer.posts()?.len(),
+        redirects: renderer.redirects()?.len(),
+    }))
+}
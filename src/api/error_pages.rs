@@ -0,0 +1,174 @@
+use actix_web::{
+    dev::ServiceResponse,
+    http::header::{HeaderValue, ACCEPT, CONTENT_TYPE},
+    middleware::{ErrorHandlerResponse, ErrorHandlers},
+    web, HttpRequest, HttpResponse, Result,
+};
+use log::error;
+
+use crate::apierror::ApiError;
+use crate::renderer::Renderer;
+
+/// Replaces error responses with a themed HTML page for browsers. API clients, which
+/// don't ask for `text/html`, keep the JSON body from `ApiError`.
+pub fn error_pages<B: 'static>() -> ErrorHandlers<B> {
+    ErrorHandlers::new().default_handler(html_error_page)
+}
+
+fn html_error_page<B>(res: ServiceResponse<B>) -> Result<ErrorHandlerResponse<B>> {
+    let request = res.request();
+    let renderer = request.app_data::<web::Data<Renderer>>();
+
+    let Some(renderer) = renderer.filter(|_| wants_html(request)) else {
+        return Ok(ErrorHandlerResponse::Response(res.map_into_left_body()));
+    };
+
+    let status = res.status();
+    let error = res.response().error();
+    // Server error details stay in the logs
+    let message = match error {
+        Some(error) if status.is_client_error() => error.to_string(),
+        _ => String::new(),
+    };
+    let error_type = error
+        .and_then(|error| error.as_error::<ApiError>())
+        .map_or_else(|| "ERROR".to_string(), ApiError::error_type);
+
+    let html = renderer.render_error_page(
+        status.as_u16(),
+        status.canonical_reason().unwrap_or("Error"),
+        &message,
+        &error_type,
+    );
+
+    match html {
+        Ok(html) => {
+            let (request, response) = res.into_parts();
+            let mut page = HttpResponse::with_body(status, html).map_into_boxed_body();
+            for (name, value) in response.headers() {
+                if name != CONTENT_TYPE {
+                    page.headers_mut().append(name.clone(), value.clone());
+                }
+            }
+            page.headers_mut().insert(
+                CONTENT_TYPE,
+                HeaderValue::from_static("text/html; charset=utf-8"),
+            );
+
+            Ok(ErrorHandlerResponse::Response(
+                ServiceResponse::new(request, page).map_into_right_body::<B>(),
+            ))
+        }
+        Err(e) => {
+            error!("Failed to render error page: {}", e);
+            Ok(ErrorHandlerResponse::Response(res.map_into_left_body()))
+        }
+    }
+}
+
+fn wants_html(request: &HttpRequest) -> bool {
+    request
+        .headers()
+        .get(ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::App;
+
+    use super::*;
+    use crate::api::routes::{blog_routes, PageVersions};
+    use crate::compression::{CompressionCache, CompressionConfig};
+    use crate::config::CacheControlConfig;
+    use crate::renderer::fixtures::fixture;
+
+    /// Status, content type and body of `uri` on the blog routes (plus a failing
+    /// `/boom`), with `posts` in the blog.
+    async fn get(
+        name: &str,
+        posts: &[(&str, &str)],
+        uri: &str,
+        accept: Option<&str>,
+    ) -> (StatusCode, String, String) {
+        let app = init_service(
+            App::new()
+                .wrap(error_pages())
+                .app_data(web::Data::new(fixture(name, posts)))
+                .app_data(web::Data::new(CacheControlConfig::default()))
+                .app_data(web::Data::new(CompressionCache::new(
+                    CompressionConfig::default(),
+                )))
+                .app_data(web::Data::new(PageVersions::default()))
+                .route(
+                    "/boom",
+                    web::get().to(|| async {
+                        Err::<HttpResponse, _>(ApiError::internal_error("database password"))
+                    }),
+                )
+                .service(blog_routes()),
+        )
+        .await;
+
+        let mut request = TestRequest::get().uri(uri);
+        if let Some(accept) = accept {
+            request = request.insert_header((ACCEPT, accept));
+        }
+        let response = call_service(&app, request.to_request()).await;
+        let status = response.status();
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let body = String::from_utf8(read_body(response).await.to_vec()).unwrap();
+        (status, content_type, body)
+    }
+
+    #[actix_web::test]
+    async fn browsers_get_html_and_api_clients_json() {
+        let html = "text/html,application/xhtml+xml;q=0.9";
+        let (status, content_type, body) =
+            get("errors_html", &[], "/blog/missing", Some(html)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(content_type, "text/html; charset=utf-8");
+        assert!(body.contains("404 Not Found"), "{}", body);
+        assert!(body.contains("Failed to read file: missing"));
+
+        for accept in [None, Some("application/json")] {
+            let (status, content_type, body) =
+                get("errors_json", &[], "/blog/missing", accept).await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+            assert_eq!(content_type, "application/json");
+            let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(json["error"], "FILE_READ_ERROR", "{}", body);
+        }
+    }
+
+    #[actix_web::test]
+    async fn custom_pages_replace_the_built_in_one() {
+        let posts = [(
+            "404.md",
+            "<!--\ntitle: Lost\n-->\n# Lost\n\nNothing at {message} ({error})\n",
+        )];
+        let (status, _, body) = get("errors_custom", &posts, "/blog/gone", Some("text/html")).await;
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(body.contains("<title>Lost</title>"), "{}", body);
+        assert!(body.contains("Nothing at Failed to read file: gone (FILE_READ_ERROR)"));
+    }
+
+    #[actix_web::test]
+    async fn server_error_details_are_hidden() {
+        let posts = [("500.md", "# Broken\n\nDetails: ({message})\n")];
+        let (status, _, body) = get("errors_hidden", &posts, "/boom", Some("text/html")).await;
+
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(body.contains("Details: ()"), "{}", body);
+        assert!(!body.contains("database password"));
+    }
+}
@@ -1 +1,4 @@
+pub mod admin;
+pub mod error_pages;
+pub mod posts;
 pub mod routes;
@@ -0,0 +1,603 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use actix_web::error::JsonPayloadError;
+use actix_web::http::header::{ETag, EntityTag, Header, IfMatch, IfNoneMatch, LOCATION};
+use actix_web::rt::time;
+use actix_web::{delete, get, patch, post, put, web, HttpRequest, HttpResponse, Scope};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::admin;
+use crate::apierror::ApiError;
+use crate::auth::{self, Role};
+use crate::config::ApiConfig;
+use crate::renderer::{
+    posts::{parse_date, Post},
+    search::SearchQuery,
+    store::Precondition,
+    RenderedContent, Renderer, Route, TocEntry, BLOG_URL_PREFIX,
+};
+
+const DEFAULT_PER_PAGE: usize = 10;
+const MAX_PER_PAGE: usize = 100;
+
+/// Room in JSON bodies for fields besides the markdown, e.g. metadata
+const JSON_BODY_OVERHEAD: usize = 16 * 1024;
+
+#[derive(Deserialize)]
+struct ListQuery {
+    page: Option<usize>,
+    per_page: Option<usize>,
+    tag: Option<String>,
+    author: Option<String>,
+    /// Only posts published on or after this date
+    from: Option<String>,
+    /// Only posts published on or before this date
+    to: Option<String>,
+    /// `date` (default) or `title`
+    sort: Option<String>,
+    /// `desc` (default for dates) or `asc`
+    order: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SearchParams {
+    q: Option<String>,
+    /// Comma-separated tags results must all have
+    tag: Option<String>,
+    page: Option<usize>,
+    per_page: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct PostQuery {
+    preview: Option<String>,
+}
+
+#[derive(Serialize)]
+struct PostSummary {
+    slug: String,
+    url: String,
+    title: String,
+    date: Option<String>,
+    author: Option<String>,
+    description: Option<String>,
+    tags: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct PostList {
+    posts: Vec<PostSummary>,
+    page: usize,
+    per_page: usize,
+    total: usize,
+    total_pages: usize,
+}
+
+#[derive(Serialize)]
+struct SearchResult {
+    #[serde(flatten)]
+    summary: PostSummary,
+    score: f64,
+    /// Excerpt of the post with matching words in `<mark>`
+    snippet: String,
+}
+
+#[derive(Serialize)]
+struct SearchResults {
+    query: String,
+    results: Vec<SearchResult>,
+    page: usize,
+    per_page: usize,
+    total: usize,
+    total_pages: usize,
+}
+
+#[derive(Serialize)]
+struct PostDetail {
+    #[serde(flatten)]
+    summary: PostSummary,
+    metadata: HashMap<String, String>,
+    html: String,
+    toc: Vec<TocEntry>,
+    word_count: usize,
+    /// Estimated minutes to read
+    reading_time: usize,
+    excerpt: String,
+    /// Older neighbour in listings
+    prev: Option<PostSummary>,
+    /// Newer neighbour in listings
+    next: Option<PostSummary>,
+    related: Vec<PostSummary>,
+    series: Option<SeriesInfo>,
+}
+
+#[derive(Serialize)]
+struct SeriesInfo {
+    name: String,
+    slug: String,
+    url: String,
+    /// 1-based position of the post in the series
+    part: usize,
+    parts: Vec<PostSummary>,
+}
+
+#[derive(Deserialize)]
+struct RenderRequest {
+    markdown: String,
+    /// Overrides metadata from the markdown's own comment block
+    #[serde(default)]
+    metadata: Option<HashMap<String, String>>,
+    /// Return the page with the template applied instead of the content alone
+    #[serde(default)]
+    full_page: bool,
+}
+
+#[derive(Deserialize)]
+struct SavePostRequest {
+    markdown: String,
+}
+
+#[derive(Deserialize)]
+struct UpdatePostRequest {
+    /// Values replace existing keys, `null` removes them
+    #[serde(default)]
+    metadata: HashMap<String, Option<String>>,
+    /// Replaces the markdown after the metadata block
+    content: Option<String>,
+}
+
+#[derive(Serialize)]
+struct RenderedMarkdown {
+    metadata: HashMap<String, String>,
+    html: String,
+    toc: Vec<TocEntry>,
+    word_count: usize,
+    reading_time: usize,
+    excerpt: String,
+}
+
+impl From<&Post> for PostSummary {
+    fn from(post: &Post) -> Self {
+        PostSummary {
+            slug: post.url.clone(),
+            url: post.href(),
+            title: post.title().to_string(),
+            date: post.date().map(|date| date.to_rfc3339()),
+            author: post.metadata.get("author").cloned(),
+            description: post.metadata.get("description").cloned(),
+            tags: post.tags(),
+        }
+    }
+}
+
+/// Listed posts, filtered, sorted and paginated.
+#[get("/posts")]
+async fn list_posts(
+    query: web::Query<ListQuery>,
+    renderer: web::Data<Renderer>,
+) -> Result<HttpResponse, ApiError> {
+    let (page, per_page) = pagination(query.page, query.per_page)?;
+
+    let from = query.from.as_deref().map(parse_filter_date).transpose()?;
+    let to = query.to.as_deref().map(parse_filter_date).transpose()?;
+
+    let mut posts: Vec<Post> = renderer
+        .listed_posts()?
+        .into_iter()
+        .filter(|post| {
+            query.tag.as_ref().is_none_or(|tag| {
+                post.tags()
+                    .iter()
+                    .any(|post_tag| post_tag.eq_ignore_ascii_case(tag))
+            })
+        })
+        .filter(|post| {
+            query.author.as_ref().is_none_or(|author| {
+                post.metadata
+                    .get("author")
+                    .is_some_and(|post_author| post_author.eq_ignore_ascii_case(author))
+            })
+        })
+        .filter(|post| from.is_none_or(|from| post.date().is_some_and(|date| date >= from)))
+        .filter(|post| to.is_none_or(|to| post.date().is_some_and(|date| date <= to)))
+        .collect();
+
+    // Listed posts come newest first
+    match query.sort.as_deref().unwrap_or("date") {
+        "date" => {}
+        "title" => posts.sort_by_key(|post| post.title().to_lowercase()),
+        other => return Err(ApiError::bad_request(format!("Unknown sort: {}", other))),
+    }
+    let default_order = if query.sort.as_deref() == Some("title") {
+        "asc"
+    } else {
+        "desc"
+    };
+    match query.order.as_deref().unwrap_or(default_order) {
+        order if order == default_order => {}
+        "asc" | "desc" => posts.reverse(),
+        other => return Err(ApiError::bad_request(format!("Unknown order: {}", other))),
+    }
+
+    let total = posts.len();
+    let posts = posts
+        .iter()
+        .skip((page - 1) * per_page)
+        .take(per_page)
+        .map(PostSummary::from)
+        .collect();
+
+    Ok(HttpResponse::Ok().json(PostList {
+        posts,
+        page,
+        per_page,
+        total,
+        total_pages: total.div_ceil(per_page),
+    }))
+}
+
+/// Listed posts matching `q`, best first, with highlighted excerpts.
+#[get("/search")]
+async fn search_posts(
+    query: web::Query<SearchParams>,
+    renderer: web::Data<Renderer>,
+) -> Result<HttpResponse, ApiError> {
+    let (page, per_page) = pagination(query.page, query.per_page)?;
+    let text = query.q.as_deref().unwrap_or_default();
+    if text.trim().is_empty() {
+        return Err(ApiError::bad_request("q must not be empty"));
+    }
+
+    let hits = renderer.search(&SearchQuery::new(text, query.tag.as_deref()))?;
+    let total = hits.len();
+    let results = hits
+        .into_iter()
+        .skip((page - 1) * per_page)
+        .take(per_page)
+        .map(|hit| SearchResult {
+            summary: PostSummary::from(&hit.post),
+            score: hit.score,
+            snippet: hit.snippet,
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(SearchResults {
+        query: text.to_string(),
+        results,
+        page,
+        per_page,
+        total,
+        total_pages: total.div_ceil(per_page),
+    }))
+}
+
+/// A post's metadata and rendered content, by its URL relative to the blog.
+#[get("/posts/{slug:.*}")]
+async fn get_post(
+    req: HttpRequest,
+    slug: web::Path<String>,
+    query: web::Query<PostQuery>,
+    renderer: web::Data<Renderer>,
+) -> Result<HttpResponse, ApiError> {
+    let preview = auth::preview(&req, query.preview.as_deref());
+    let post = match renderer.route(&slug.into_inner(), preview)? {
+        Route::Post(post) => post,
+        // Follow aliases of moved posts to their new API URL
+        Route::Redirect(redirect) => match redirect.to.strip_prefix(BLOG_URL_PREFIX) {
+            Some(path) => {
+                return Ok(HttpResponse::MovedPermanently()
+                    .insert_header((LOCATION, format!("/api/posts{}", path)))
+                    .finish())
+            }
+            None => return Err(ApiError::not_found(redirect.to)),
+        },
+    };
+
+    let content = renderer.render_fragment(&post)?;
+    let navigation = renderer.navigation(&post)?;
+    let series = renderer.series_of(&post)?.map(|series| SeriesInfo {
+        part: series.position(&post).map_or(0, |i| i + 1),
+        url: series.href(),
+        parts: series.parts.iter().map(PostSummary::from).collect(),
+        name: series.name,
+        slug: series.slug,
+    });
+    let (_, _, etag) = renderer.post_source(&post.url)?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header(ETag(EntityTag::new_strong(etag)))
+        .json(PostDetail {
+            summary: PostSummary::from(&post),
+            metadata: content.metadata,
+            html: content.html,
+            toc: content.toc,
+            word_count: content.word_count,
+            reading_time: content.reading_time,
+            excerpt: content.excerpt,
+            prev: navigation.prev.as_ref().map(PostSummary::from),
+            next: navigation.next.as_ref().map(PostSummary::from),
+            related: navigation.related.iter().map(PostSummary::from).collect(),
+            series,
+        }))
+}
+
+/// Creates or replaces a post with the submitted markdown.
+#[put("/posts/{slug:.*}")]
+async fn save_post(
+    req: HttpRequest,
+    slug: web::Path<String>,
+    body: web::Json<SavePostRequest>,
+    renderer: web::Data<Renderer>,
+    config: web::Data<ApiConfig>,
+) -> Result<HttpResponse, ApiError> {
+    auth::require(&req, Role::Author)?;
+    let markdown = body.into_inner().markdown;
+    if markdown.len() > config.render_max_bytes {
+        return Err(ApiError::validation_error(format!(
+            "markdown exceeds {} bytes",
+            config.render_max_bytes
+        )));
+    }
+
+    let (post, etag, created) = renderer.save_post(&slug, &markdown, &precondition(&req)?)?;
+
+    let mut response = if created {
+        let mut response = HttpResponse::Created();
+        response.insert_header((LOCATION, format!("/api/posts/{}", post.url)));
+        response
+    } else {
+        HttpResponse::Ok()
+    };
+    Ok(response
+        .insert_header(ETag(EntityTag::new_strong(etag)))
+        .json(PostSummary::from(&post)))
+}
+
+/// Changes some metadata keys and/or the content of an existing post.
+#[patch("/posts/{slug:.*}")]
+async fn update_post(
+    req: HttpRequest,
+    slug: web::Path<String>,
+    body: web::Json<UpdatePostRequest>,
+    renderer: web::Data<Renderer>,
+) -> Result<HttpResponse, ApiError> {
+    auth::require(&req, Role::Author)?;
+    let body = body.into_inner();
+    let mut metadata: Vec<(String, Option<String>)> = body.metadata.into_iter().collect();
+    metadata.sort();
+
+    let (post, etag) = renderer.update_post(
+        &slug,
+        &metadata,
+        body.content.as_deref(),
+        &precondition(&req)?,
+    )?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header(ETag(EntityTag::new_strong(etag)))
+        .json(PostSummary::from(&post)))
+}
+
+#[delete("/posts/{slug:.*}")]
+async fn delete_post(
+    req: HttpRequest,
+    slug: web::Path<String>,
+    renderer: web::Data<Renderer>,
+) -> Result<HttpResponse, ApiError> {
+    auth::require(&req, Role::Editor)?;
+    renderer.delete_post(&slug, &precondition(&req)?)?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Renders submitted markdown to an HTML fragment or a full page, e.g. for an
+/// editor preview.
+#[post("/render")]
+async fn render_markdown(
+    req: HttpRequest,
+    body: web::Json<RenderRequest>,
+    renderer: web::Data<Renderer>,
+    config: web::Data<ApiConfig>,
+) -> Result<HttpResponse, ApiError> {
+    // Rendering is expensive, keep it to people who write
+    auth::require(&req, Role::Author)?;
+    let RenderRequest {
+        markdown,
+        metadata,
+        full_page,
+    } = body.into_inner();
+    if markdown.trim().is_empty() {
+        return Err(ApiError::validation_error("markdown must not be empty"));
+    }
+    if markdown.len() > config.render_max_bytes {
+        return Err(ApiError::validation_error(format!(
+            "markdown exceeds {} bytes",
+            config.render_max_bytes
+        )));
+    }
+
+    // The render stops itself at the deadline, the timeout only covers what can't be
+    // interrupted, e.g. laying out a diagram
+    let timeout = Duration::from_millis(config.render_timeout_ms);
+    let timed_out = || {
+        ApiError::service_unavailable(format!(
+            "Rendering took longer than {} ms",
+            config.render_timeout_ms
+        ))
+    };
+    let rendering = web::block(move || {
+        let mut content = renderer.render_source(&markdown, Some(Instant::now() + timeout))?;
+        content.metadata.extend(metadata.unwrap_or_default());
+        let rendered = if full_page {
+            let metadata = content.metadata.clone();
+            let toc = content.toc.clone();
+            let (word_count, reading_time, excerpt) = (
+                content.word_count,
+                content.reading_time,
+                content.excerpt.clone(),
+            );
+            RenderedMarkdown {
+                html: renderer.render_content_page(content)?,
+                metadata,
+                toc,
+                word_count,
+                reading_time,
+                excerpt,
+            }
+        } else {
+            RenderedMarkdown::from(content)
+        };
+        Ok::<_, ApiError>(rendered)
+    });
+    let rendered = match time::timeout(timeout, rendering).await {
+        Ok(result) => result
+            .map_err(ApiError::internal_error)?
+            .map_err(|e| match e {
+                ApiError::ServiceUnavailable(_) => timed_out(),
+                e => e,
+            })?,
+        Err(_) => return Err(timed_out()),
+    };
+
+    Ok(HttpResponse::Ok().json(rendered))
+}
+
+impl From<RenderedContent> for RenderedMarkdown {
+    fn from(content: RenderedContent) -> Self {
+        RenderedMarkdown {
+            metadata: content.metadata,
+            html: content.html,
+            toc: content.toc,
+            word_count: content.word_count,
+            reading_time: content.reading_time,
+            excerpt: content.excerpt,
+        }
+    }
+}
+
+/// Reads the edit precondition from `If-Match` or `If-None-Match: *`.
+fn precondition(req: &HttpRequest) -> Result<Precondition, ApiError> {
+    if req.headers().contains_key(IfMatch::name()) {
+        return match IfMatch::parse(req).map_err(|e| ApiError::bad_request(e.to_string()))? {
+            IfMatch::Any => Ok(Precondition::Exists),
+            // If-Match uses the strong comparison, a weak tag never matches
+            IfMatch::Items(tags) => Ok(Precondition::Matches(
+                tags.into_iter()
+                    .filter(|tag| !tag.weak)
+                    .map(|tag| tag.tag().to_string())
+                    .collect(),
+            )),
+        };
+    }
+
+    match IfNoneMatch::parse(req) {
+        Ok(IfNoneMatch::Any) => Ok(Precondition::Absent),
+        _ => Ok(Precondition::None),
+    }
+}
+
+/// Validated `page` and `per_page` query parameters.
+fn pagination(page: Option<usize>, per_page: Option<usize>) -> Result<(usize, usize), ApiError> {
+    let page = page.unwrap_or(1);
+    let per_page = per_page.unwrap_or(DEFAULT_PER_PAGE);
+    if page == 0 {
+        return Err(ApiError::bad_request("page starts at 1"));
+    }
+    if per_page == 0 || per_page > MAX_PER_PAGE {
+        return Err(ApiError::bad_request(format!(
+            "per_page must be between 1 and {}",
+            MAX_PER_PAGE
+        )));
+    }
+    Ok((page, per_page))
+}
+
+fn parse_filter_date(value: &str) -> Result<DateTime<Utc>, ApiError> {
+    parse_date(value).ok_or_else(|| ApiError::bad_request(format!("Invalid date: {}", value)))
+}
+
+/// Reports malformed or oversized JSON bodies as validation errors.
+fn json_error(error: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    let message = match &error {
+        JsonPayloadError::OverflowKnownLength { limit, .. }
+        | JsonPayloadError::Overflow { limit } => {
+            format!("Request body exceeds {} bytes", limit)
+        }
+        _ => error.to_string(),
+    };
+    ApiError::validation_error(message).into()
+}
+
+pub fn api_routes(config: ApiConfig) -> Scope {
+    // JSON escaping can make the body up to six times larger than the markdown it
+    // carries (`\u0000`), the markdown itself is checked against `render_max_bytes`
+    let json_config = web::JsonConfig::default()
+        .limit(config.render_max_bytes * 6 + JSON_BODY_OVERHEAD)
+        .error_handler(json_error);
+
+    web::scope("/api")
+        .app_data(json_config)
+        .app_data(web::Data::new(config))
+        .service(list_posts)
+        .service(search_posts)
+        .service(get_post)
+        .service(save_post)
+        .service(update_post)
+        .service(delete_post)
+        .service(render_markdown)
+        .service(admin::create_session)
+        .service(admin::get_session)
+        .service(admin::delete_session)
+        .service(admin::preview_link)
+        .service(admin::reload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::fixtures::fixture;
+    use actix_web::test::{call_and_read_body_json, init_service, TestRequest};
+    use actix_web::App;
+
+    #[test]
+    fn if_match_ignores_weak_tags() {
+        let req = TestRequest::default()
+            .insert_header((IfMatch::name(), r#"W/"abc", "def""#))
+            .to_http_request();
+
+        match precondition(&req).unwrap() {
+            Precondition::Matches(tags) => assert_eq!(tags, vec!["def".to_string()]),
+            _ => panic!("expected ETags"),
+        }
+        assert!(!Precondition::Matches(Vec::new()).holds(Some("abc")));
+    }
+
+    #[actix_web::test]
+    async fn hidden_and_unlisted_posts_are_not_listed() {
+        let post = |extra: &str| format!("<!--\ntitle: Post\n{}-->\n# Post\n", extra);
+        let renderer = fixture(
+            "api_list",
+            &[
+                ("listed.md", &post("date: 2024-01-02\n")),
+                ("unlisted.md", &post("unlisted: true\n")),
+                ("draft.md", &post("draft: true\n")),
+                ("future.md", &post("date: 2999-01-01\n")),
+            ],
+        );
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(renderer))
+                .service(api_routes(ApiConfig::default())),
+        )
+        .await;
+
+        let list: serde_json::Value =
+            call_and_read_body_json(&app, TestRequest::get().uri("/api/posts").to_request()).await;
+        assert_eq!(list["total"], 1);
+        assert_eq!(list["posts"][0]["slug"], "listed");
+    }
+}
@@ -1,20 +1,608 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
 use crate::apierror::ApiError;
-use crate::renderer::Renderer;
-use actix_web::{get, web, HttpResponse, Scope};
+use crate::auth;
+use crate::compression::{CompressionCache, Encoding};
+use crate::config::CacheControlConfig;
+use crate::renderer::error::RendererError;
+use crate::renderer::{
+    is_asset_request,
+    paths::resolve_within,
+    search::{self, SearchQuery},
+    Preview, Renderer, Route, BLOG_URL_PREFIX,
+};
+use actix_files::NamedFile;
+use actix_web::http::header::{
+    EntityTag, HeaderValue, HttpDate, IfModifiedSince, IfNoneMatch, LastModified, ACCEPT_ENCODING,
+    CACHE_CONTROL, CONTENT_ENCODING, ETAG, LOCATION, VARY,
+};
+use actix_web::http::StatusCode;
+use actix_web::{get, web, HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder, Scope};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+const PREVIEW_CACHE_CONTROL: &str = "private, no-store";
+
+/// Root directory of the files served under `/static`
+pub struct StaticDir(pub PathBuf);
+
+#[derive(Deserialize)]
+struct PageQuery {
+    /// Signed token from a preview link, see `Renderer::preview_url`
+    preview: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SearchPageQuery {
+    q: Option<String>,
+    tag: Option<String>,
+}
+
+/// Search form and results, rendered with the page template.
+#[get("/search")]
+async fn search_page(
+    req: HttpRequest,
+    query: web::Query<SearchPageQuery>,
+    renderer: web::Data<Renderer>,
+    cache_control: web::Data<CacheControlConfig>,
+    compression: web::Data<CompressionCache>,
+) -> Result<HttpResponse, ApiError> {
+    let text = query.q.as_deref().unwrap_or_default();
+    let hits = renderer.search(&SearchQuery::new(text, query.tag.as_deref()))?;
+    let title = match text.trim() {
+        "" => "Search".to_string(),
+        text => format!("Search: {}", text),
+    };
+    let html = renderer.render_page(
+        &title,
+        search::search_page_html(text, query.tag.as_deref(), &hits),
+    )?;
+
+    let version = PageVersion::new(&html);
+    Ok(page_response(
+        &req,
+        html,
+        &version,
+        None,
+        &cache_control.pages,
+        &compression,
+    ))
+}
+
+/// The parts of a series, in order.
+#[get("/series/{slug}")]
+async fn series_page(
+    req: HttpRequest,
+    slug: web::Path<String>,
+    renderer: web::Data<Renderer>,
+    cache_control: web::Data<CacheControlConfig>,
+    compression: web::Data<CompressionCache>,
+) -> Result<HttpResponse, ApiError> {
+    let series = renderer.series(&slug)?;
+    let html = renderer.render_page(&series.name, series.page_html())?;
+
+    let version = PageVersion::new(&html);
+    Ok(page_response(
+        &req,
+        html,
+        &version,
+        None,
+        &cache_control.pages,
+        &compression,
+    ))
+}
 
 #[get("/{path:.*}")]
 async fn render_blog_page(
+    req: HttpRequest,
     path: web::Path<String>,
+    query: web::Query<PageQuery>,
     renderer: web::Data<Renderer>,
+    cache_control: web::Data<CacheControlConfig>,
+    compression: web::Data<CompressionCache>,
+    versions: web::Data<PageVersions>,
 ) -> Result<HttpResponse, ApiError> {
     let path_str = path.into_inner();
 
-    // Render the markdown
-    let html = renderer.render(&path_str)?;
+    // Files next to the posts, e.g. images. Missing ones may still be redirected.
+    if is_asset_request(&path_str) {
+        if let Ok(asset_path) = renderer.asset_path(&path_str) {
+            return file_response(&req, asset_path, &cache_control.assets);
+        }
+    }
+
+    let preview = auth::preview(&req, query.preview.as_deref());
+    let post = match renderer.route(&path_str, preview)? {
+        Route::Post(post) => post,
+        Route::Redirect(redirect) => {
+            let status =
+                StatusCode::from_u16(redirect.status).unwrap_or(StatusCode::MOVED_PERMANENTLY);
+            return Ok(HttpResponse::build(status)
+                .insert_header((LOCATION, redirect.to))
+                .finish());
+        }
+    };
+
+    // Keep previews of unpublished posts out of shared caches
+    let page_cache_control = match preview {
+        Preview::None => &cache_control.pages,
+        _ => PREVIEW_CACHE_CONTROL,
+    };
+
+    // The client's copy may be current without rendering the post again
+    let last_modified = renderer.last_modified(&post);
+    let known = last_modified.and_then(|modified| versions.get(&post.url, modified));
+    if let Some(response) = not_modified(
+        &req,
+        known.as_ref(),
+        last_modified,
+        page_cache_control,
+        &compression,
+    ) {
+        return Ok(response);
+    }
+
+    // Rendering may resize images, keep it off the worker thread
+    let html = web::block({
+        let renderer = renderer.clone();
+        let post = post.clone();
+        move || renderer.render(&post)
+    })
+    .await
+    .map_err(ApiError::internal_error)??;
+
+    let version = PageVersion::new(&html);
+    if let Some(modified) = last_modified {
+        versions.insert(&post.url, modified, version.clone());
+    }
+
+    Ok(page_response(
+        &req,
+        html,
+        &version,
+        last_modified,
+        page_cache_control,
+        &compression,
+    ))
+}
 
-    Ok(HttpResponse::Ok().content_type("text/html").body(html))
+/// A rendered page as far as conditional requests are concerned: a hash of its
+/// HTML and its length, which decides whether it is compressed.
+#[derive(Clone)]
+pub struct PageVersion {
+    hash: String,
+    len: usize,
+}
+
+impl PageVersion {
+    pub fn new(html: &str) -> Self {
+        let digest = Sha256::digest(html.as_bytes());
+        PageVersion {
+            hash: digest[..16].iter().map(|b| format!("{:02x}", b)).collect(),
+            len: html.len(),
+        }
+    }
+
+    /// Each encoding is a different representation and needs its own strong ETag
+    fn etag(&self, encoding: Option<Encoding>) -> EntityTag {
+        match encoding {
+            Some(encoding) => EntityTag::new_strong(format!("{}-{}", self.hash, encoding.name())),
+            None => EntityTag::new_strong(self.hash.clone()),
+        }
+    }
+}
+
+/// The version each post was last rendered at, with the modification time it had
+/// then, so conditional requests for unchanged posts are answered without
+/// rendering them.
+#[derive(Default)]
+pub struct PageVersions(Mutex<HashMap<String, (SystemTime, PageVersion)>>);
+
+impl PageVersions {
+    fn get(&self, url: &str, modified: SystemTime) -> Option<PageVersion> {
+        let versions = self.0.lock().ok()?;
+        versions
+            .get(url)
+            .filter(|(rendered_at, _)| *rendered_at == modified)
+            .map(|(_, version)| version.clone())
+    }
+
+    fn insert(&self, url: &str, modified: SystemTime, version: PageVersion) {
+        if let Ok(mut versions) = self.0.lock() {
+            versions.insert(url.to_string(), (modified, version));
+        }
+    }
+}
+
+/// Responds with a rendered page, compressed if the client accepts it, or with
+/// `304 Not Modified` when the client's copy is current, see `not_modified`.
+fn page_response(
+    req: &HttpRequest,
+    html: String,
+    version: &PageVersion,
+    last_modified: Option<SystemTime>,
+    cache_control: &str,
+    compression: &CompressionCache,
+) -> HttpResponse {
+    if let Some(response) = not_modified(
+        req,
+        Some(version),
+        last_modified,
+        cache_control,
+        compression,
+    ) {
+        return response;
+    }
+
+    let compressed = compression.compress(accept_encoding(req), &version.hash, html.as_bytes());
+    let etag = version.etag(compressed.as_ref().map(|(encoding, _)| *encoding));
+
+    let mut response = HttpResponse::Ok();
+    validator_headers(
+        &mut response,
+        Some(&etag),
+        http_date(last_modified),
+        cache_control,
+        compression,
+    );
+    response.content_type("text/html");
+    match compressed {
+        Some((encoding, body)) => response
+            .insert_header((CONTENT_ENCODING, encoding.name()))
+            .body(body),
+        None => response.body(html),
+    }
+}
+
+/// `304 Not Modified` if the client's copy matches the ETag of `version` or, without
+/// `If-None-Match`, is not older than `last_modified`. Works without a `version`
+/// too, so it can be checked before rendering.
+fn not_modified(
+    req: &HttpRequest,
+    version: Option<&PageVersion>,
+    last_modified: Option<SystemTime>,
+    cache_control: &str,
+    compression: &CompressionCache,
+) -> Option<HttpResponse> {
+    let etag = version
+        .map(|version| version.etag(compression.encoding(accept_encoding(req), version.len)));
+    let last_modified = http_date(last_modified);
+
+    let is_current = match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => etag
+            .as_ref()
+            .is_some_and(|etag| tags.iter().any(|tag| tag.weak_eq(etag))),
+        None => match (req.get_header::<IfModifiedSince>(), last_modified) {
+            (Some(IfModifiedSince(since)), Some(modified)) => {
+                SystemTime::from(modified) <= SystemTime::from(since)
+            }
+            _ => false,
+        },
+    };
+    if !is_current {
+        return None;
+    }
+
+    let mut response = HttpResponse::NotModified();
+    validator_headers(
+        &mut response,
+        etag.as_ref(),
+        last_modified,
+        cache_control,
+        compression,
+    );
+    Some(response.finish())
+}
+
+/// Headers a page and its `304 Not Modified` share.
+fn validator_headers(
+    response: &mut HttpResponseBuilder,
+    etag: Option<&EntityTag>,
+    last_modified: Option<HttpDate>,
+    cache_control: &str,
+    compression: &CompressionCache,
+) {
+    if let Some(etag) = etag {
+        response.insert_header((ETAG, etag.to_string()));
+    }
+    if let Some(modified) = last_modified {
+        response.insert_header(LastModified(modified));
+    }
+    if let Ok(value) = HeaderValue::from_str(cache_control) {
+        response.insert_header((CACHE_CONTROL, value));
+    }
+    if compression.enabled() {
+        response.insert_header((VARY, "Accept-Encoding"));
+    }
+}
+
+fn accept_encoding(req: &HttpRequest) -> &str {
+    req.headers()
+        .get(ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+}
+
+/// HTTP dates have second precision, so validators compare the truncated time
+fn http_date(time: Option<SystemTime>) -> Option<HttpDate> {
+    time.and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|since_epoch| HttpDate::from(UNIX_EPOCH + Duration::from_secs(since_epoch.as_secs())))
+}
+
+#[get("/{file}")]
+async fn image_variant(
+    req: HttpRequest,
+    file: web::Path<String>,
+    renderer: web::Data<Renderer>,
+    cache_control: web::Data<CacheControlConfig>,
+) -> Result<HttpResponse, ApiError> {
+    let variant_path = renderer.image_variant_path(&file.into_inner())?;
+    file_response(&req, variant_path, &cache_control.image_variants)
+}
+
+#[get("/{path:.*}")]
+async fn static_file(
+    req: HttpRequest,
+    path: web::Path<String>,
+    static_dir: web::Data<StaticDir>,
+    cache_control: web::Data<CacheControlConfig>,
+) -> Result<HttpResponse, ApiError> {
+    let path_str = path.into_inner();
+    if path_str.split('/').any(|segment| segment.starts_with('.')) {
+        return Err(ApiError::not_found(path_str));
+    }
+
+    // Report the request path, not where the file would be on disk
+    let file_path = resolve_within(&static_dir.0, &path_str).map_err(|e| match e {
+        RendererError::FileReadError { .. } => ApiError::not_found(req.path()),
+        e => e.into(),
+    })?;
+    file_response(&req, file_path, &cache_control.static_files)
+}
+
+/// Serves a file with its MIME type, `ETag`, `Last-Modified` and the given
+/// `Cache-Control`. Conditional requests are answered with `304 Not Modified`.
+fn file_response(
+    req: &HttpRequest,
+    path: PathBuf,
+    cache_control: &str,
+) -> Result<HttpResponse, ApiError> {
+    if !path.is_file() {
+        return Err(ApiError::not_found(req.path()));
+    }
+
+    let file = NamedFile::open(&path).map_err(|e| RendererError::FileReadError {
+        path: req.path().to_string(),
+        source: e,
+    })?;
+
+    let mut response = file
+        .use_etag(true)
+        .use_last_modified(true)
+        .into_response(req);
+    if let Ok(value) = HeaderValue::from_str(cache_control) {
+        response.headers_mut().insert(CACHE_CONTROL, value);
+    }
+
+    Ok(response)
 }
 
 pub fn blog_routes() -> Scope {
-    web::scope("/blog").service(render_blog_page)
+    web::scope(BLOG_URL_PREFIX)
+        .service(search_page)
+        .service(series_page)
+        .service(render_blog_page)
+}
+
+pub fn image_routes(url_prefix: &str) -> Scope {
+    web::scope(url_prefix.trim_end_matches('/')).service(image_variant)
+}
+
+pub fn static_routes(static_dir: PathBuf) -> Scope {
+    web::scope("/static")
+        .app_data(web::Data::new(StaticDir(static_dir)))
+        .service(static_file)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::App;
+
+    use super::*;
+    use crate::compression::CompressionConfig;
+    use actix_web::http::header::{HeaderName, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+
+    use crate::renderer::fixtures::{fixture, fixture_with};
+    use crate::renderer::options::PreviewOptions;
+    use crate::renderer::RenderOptions;
+
+    /// Serves the blog routes for `renderer` with default cache and compression settings.
+    fn app(
+        renderer: Renderer,
+    ) -> App<
+        impl actix_web::dev::ServiceFactory<
+            actix_web::dev::ServiceRequest,
+            Config = (),
+            Response = actix_web::dev::ServiceResponse,
+            Error = actix_web::Error,
+            InitError = (),
+        >,
+    > {
+        App::new()
+            .app_data(web::Data::new(renderer))
+            .app_data(web::Data::new(CacheControlConfig::default()))
+            .app_data(web::Data::new(CompressionCache::new(
+                CompressionConfig::default(),
+            )))
+            .app_data(web::Data::new(PageVersions::default()))
+            .service(blog_routes())
+    }
+
+    /// A post long enough to be compressed, and its path on disk.
+    fn long_post(name: &str) -> (Renderer, PathBuf) {
+        let source = format!(
+            "# Long\n\n{}\n",
+            "Some words worth compressing. ".repeat(100)
+        );
+        let renderer = fixture(name, &[("long.md", &source)]);
+        let path = std::env::temp_dir()
+            .join(format!(
+                "md_to_html_renderer_{}_{}",
+                std::process::id(),
+                name
+            ))
+            .join("blog/long.md");
+        (renderer, path)
+    }
+
+    fn get(uri: &str, accept_encoding: &str) -> TestRequest {
+        TestRequest::get()
+            .uri(uri)
+            .insert_header((ACCEPT_ENCODING, accept_encoding))
+    }
+
+    fn header(response: &actix_web::dev::ServiceResponse, name: HeaderName) -> String {
+        response
+            .headers()
+            .get(name)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[actix_web::test]
+    async fn matching_etags_are_not_modified() {
+        let (renderer, _) = long_post("routes_if_none_match");
+        let app = init_service(app(renderer)).await;
+
+        let response = call_service(&app, get("/blog/long", "gzip").to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = header(&response, ETAG);
+
+        let request = get("/blog/long", "gzip").insert_header((IF_NONE_MATCH, etag.as_str()));
+        let response = call_service(&app, request.to_request()).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(header(&response, ETAG), etag);
+        assert_eq!(header(&response, VARY), "Accept-Encoding");
+
+        // The gzip tag doesn't match the brotli representation
+        let request = get("/blog/long", "br").insert_header((IF_NONE_MATCH, etag.as_str()));
+        let response = call_service(&app, request.to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let request = get("/blog/long", "gzip").insert_header((IF_NONE_MATCH, "\"other\""));
+        let response = call_service(&app, request.to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn unchanged_posts_are_not_modified_since() {
+        let (renderer, _) = long_post("routes_if_modified_since");
+        let app = init_service(app(renderer)).await;
+
+        let response = call_service(&app, get("/blog/long", "").to_request()).await;
+        let last_modified = header(&response, LAST_MODIFIED);
+
+        let request =
+            get("/blog/long", "").insert_header((IF_MODIFIED_SINCE, last_modified.as_str()));
+        let response = call_service(&app, request.to_request()).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(header(&response, LAST_MODIFIED), last_modified);
+
+        let request = get("/blog/long", "")
+            .insert_header((IF_MODIFIED_SINCE, "Mon, 01 Jan 2001 00:00:00 GMT"));
+        let response = call_service(&app, request.to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn each_encoding_has_its_own_strong_etag() {
+        let (renderer, _) = long_post("routes_etags");
+        let app = init_service(app(renderer)).await;
+
+        let mut etags = Vec::new();
+        for accept_encoding in ["br", "gzip", "identity"] {
+            let response =
+                call_service(&app, get("/blog/long", accept_encoding).to_request()).await;
+            assert_eq!(header(&response, VARY), "Accept-Encoding");
+            etags.push(header(&response, ETAG));
+        }
+
+        assert!(etags.iter().all(|etag| !etag.starts_with("W/")));
+        assert!(etags[0].ends_with("-br\""));
+        assert!(etags[1].ends_with("-gzip\""));
+        assert_ne!(etags[2], etags[0]);
+        assert_ne!(etags[2], etags[1]);
+    }
+
+    #[actix_web::test]
+    async fn validators_are_checked_before_rendering() {
+        let (renderer, path) = long_post("routes_before_render");
+        let app = init_service(app(renderer)).await;
+
+        let response = call_service(&app, get("/blog/long", "gzip").to_request()).await;
+        let etag = header(&response, ETAG);
+
+        // Different content at the same modification time is only served if rendered
+        let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+        std::fs::write(&path, "# Changed\n").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+
+        let request = get("/blog/long", "gzip").insert_header((IF_NONE_MATCH, etag.as_str()));
+        let response = call_service(&app, request.to_request()).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(header(&response, ETAG), etag);
+    }
+
+    #[actix_web::test]
+    async fn preview_links_serve_drafts() {
+        let options = RenderOptions {
+            preview: PreviewOptions {
+                secret: Some("secret".to_string()),
+                ..PreviewOptions::default()
+            },
+            ..RenderOptions::default()
+        };
+        let renderer = fixture_with(
+            "routes_preview",
+            &[(
+                "draft.md",
+                "<!--\ntitle: Draft\ndraft: true\n-->\n# Draft\n",
+            )],
+            options,
+        );
+        let link = renderer.preview_url("draft").unwrap();
+        let app = init_service(app(renderer)).await;
+        let get = |uri: &str| TestRequest::get().uri(uri).to_request();
+
+        let response = call_service(&app, get(&link)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(CACHE_CONTROL).unwrap(),
+            PREVIEW_CACHE_CONTROL
+        );
+
+        let response = call_service(&app, get("/blog/draft")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = call_service(&app, get(&format!("{}0", link))).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let forged = format!("/blog/draft?preview=1.{}", "00".repeat(32));
+        assert_eq!(
+            call_service(&app, get(&forged)).await.status(),
+            StatusCode::FORBIDDEN
+        );
+    }
 }
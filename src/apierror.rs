@@ -24,6 +24,12 @@ pub enum ApiError {
     #[error("Validation Error: {0}")]
     ValidationError(String),
 
+    #[error("Precondition Failed: {0}")]
+    PreconditionFailed(String),
+
+    #[error("Service Unavailable: {0}")]
+    ServiceUnavailable(String),
+
     #[error(transparent)]
     RendererError(#[from] RendererError),
 }
@@ -57,6 +63,8 @@ impl ResponseError for ApiError {
             ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
             ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
             ApiError::ValidationError(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::PreconditionFailed(_) => StatusCode::PRECONDITION_FAILED,
+            ApiError::ServiceUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
             ApiError::RendererError(err) => match err {
                 RendererError::FileReadError { .. } => StatusCode::NOT_FOUND,
                 RendererError::FileWriteError { .. } => StatusCode::INTERNAL_SERVER_ERROR,
@@ -71,7 +79,7 @@ impl ResponseError for ApiError {
 }
 
 impl ApiError {
-    fn error_type(&self) -> String {
+    pub fn error_type(&self) -> String {
         match self {
             ApiError::InternalError(_) => "INTERNAL_ERROR",
             ApiError::NotFound(_) => "NOT_FOUND",
@@ -79,6 +87,8 @@ impl ApiError {
             ApiError::Unauthorized(_) => "UNAUTHORIZED",
             ApiError::Forbidden(_) => "FORBIDDEN",
             ApiError::ValidationError(_) => "VALIDATION_ERROR",
+            ApiError::PreconditionFailed(_) => "PRECONDITION_FAILED",
+            ApiError::ServiceUnavailable(_) => "SERVICE_UNAVAILABLE",
             ApiError::RendererError(err) => match err {
                 RendererError::FileReadError { .. } => "FILE_READ_ERROR",
                 RendererError::FileWriteError { .. } => "FILE_WRITE_ERROR",
@@ -118,4 +128,12 @@ impl ApiError {
     pub fn validation_error<T: ToString>(message: T) -> Self {
         ApiError::ValidationError(message.to_string())
     }
+
+    pub fn precondition_failed<T: ToString>(message: T) -> Self {
+        ApiError::PreconditionFailed(message.to_string())
+    }
+
+    pub fn service_unavailable<T: ToString>(message: T) -> Self {
+        ApiError::ServiceUnavailable(message.to_string())
+    }
 }
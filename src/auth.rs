@@ -0,0 +1,332 @@
+use std::fmt;
+
+use actix_web::{
+    body::MessageBody,
+    cookie::{time::Duration as CookieDuration, Cookie, SameSite},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::AUTHORIZATION,
+    middleware::Next,
+    web, HttpMessage, HttpRequest,
+};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::apierror::ApiError;
+use crate::renderer::Preview;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Longest session lifetime, larger `session_ttl_hours` are clamped to it
+const MAX_SESSION_TTL_HOURS: u64 = 10 * 365 * 24;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    /// API tokens, sent as `Authorization: Bearer <token>` or exchanged for a session
+    pub tokens: Vec<TokenConfig>,
+    /// Key signing session cookies, sessions are disabled without one
+    pub session_secret: Option<String>,
+    pub session_ttl_hours: u64,
+    pub cookie_name: String,
+    /// Only send the session cookie over HTTPS
+    pub secure_cookie: bool,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            tokens: Vec::new(),
+            session_secret: None,
+            session_ttl_hours: 7 * 24,
+            cookie_name: "md_session".to_string(),
+            secure_cookie: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TokenConfig {
+    /// Who the token belongs to, shown in logs and stored in sessions
+    pub name: String,
+    pub token: String,
+    pub role: Role,
+}
+
+impl TokenConfig {
+    fn identity(&self) -> Identity {
+        Identity {
+            name: self.name.clone(),
+            role: self.role,
+        }
+    }
+}
+
+/// Roles in increasing order of privilege, each including the ones before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// May read drafts and scheduled posts
+    Reader,
+    /// May also create and edit posts and create preview links
+    Author,
+    /// May also delete posts
+    Editor,
+    /// May also use the admin routes
+    Admin,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Role::Reader => "reader",
+            Role::Author => "author",
+            Role::Editor => "editor",
+            Role::Admin => "admin",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The authenticated user of a request, stored in its extensions by `authenticate`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Identity {
+    pub name: String,
+    pub role: Role,
+}
+
+pub struct Auth {
+    config: AuthConfig,
+}
+
+impl Auth {
+    pub fn new(config: AuthConfig) -> Self {
+        Auth { config }
+    }
+
+    /// Identifies a request by its bearer token or session cookie. An unknown bearer
+    /// token is an error, a stale or tampered cookie is ignored so public pages keep
+    /// working, as are other `Authorization` schemes, e.g. basic auth of a proxy.
+    pub fn identify(&self, req: &HttpRequest) -> Result<Option<Identity>, ApiError> {
+        if let Some(token) = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|header| header.to_str().ok())
+            .and_then(bearer_token)
+        {
+            // Compared through a MAC so neither the time taken nor the lengths
+            // compared depend on how much of a token is right
+            let presented = token_mac(token).finalize().into_bytes();
+            return self
+                .config
+                .tokens
+                .iter()
+                .find(|config| token_mac(&config.token).verify_slice(&presented).is_ok())
+                .map(|config| Some(config.identity()))
+                .ok_or_else(|| ApiError::unauthorized("Invalid token"));
+        }
+
+        Ok(req
+            .cookie(&self.config.cookie_name)
+            .and_then(|cookie| self.verify_session(cookie.value(), Utc::now().timestamp())))
+    }
+
+    /// Session cookie for `identity`. Sessions hold the token's name, so removing the
+    /// token from the config also ends its sessions.
+    pub fn session_cookie(&self, identity: &Identity) -> Result<Cookie<'static>, ApiError> {
+        let secret =
+            self.config.session_secret.as_ref().ok_or_else(|| {
+                ApiError::bad_request("Set auth.session_secret to enable sessions")
+            })?;
+
+        let ttl_hours = self.config.session_ttl_hours.min(MAX_SESSION_TTL_HOURS) as i64;
+        let expires = (Utc::now() + chrono::Duration::hours(ttl_hours)).timestamp();
+        let signature = mac(secret, &identity.name, expires).finalize().into_bytes();
+        let hex: String = signature.iter().map(|b| format!("{:02x}", b)).collect();
+
+        Ok(self
+            .cookie(format!("{}.{}.{}", expires, hex, identity.name))
+            .max_age(CookieDuration::hours(ttl_hours))
+            .finish())
+    }
+
+    /// Expired cookie replacing the session cookie on sign-out.
+    pub fn removal_cookie(&self) -> Cookie<'static> {
+        let mut cookie = self.cookie(String::new()).finish();
+        cookie.make_removal();
+        cookie
+    }
+
+    fn cookie(&self, value: String) -> actix_web::cookie::CookieBuilder<'static> {
+        Cookie::build(self.config.cookie_name.clone(), value)
+            .path("/")
+            .http_only(true)
+            .secure(self.config.secure_cookie)
+            .same_site(SameSite::Strict)
+    }
+
+    fn verify_session(&self, value: &str, now: i64) -> Option<Identity> {
+        let secret = self.config.session_secret.as_ref()?;
+        let mut parts = value.splitn(3, '.');
+        let expires = parts.next()?.parse::<i64>().ok()?;
+        let signature = decode_hex(parts.next()?)?;
+        let name = parts.next()?;
+
+        mac(secret, name, expires).verify_slice(&signature).ok()?;
+        if expires < now {
+            return None;
+        }
+
+        self.config
+            .tokens
+            .iter()
+            .find(|config| config.name == name)
+            .map(TokenConfig::identity)
+    }
+}
+
+/// Middleware storing the request's `Identity`, if any, for `require` and `identity`.
+pub async fn authenticate(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let identified = match req.app_data::<web::Data<Auth>>() {
+        Some(auth) => auth.identify(req.request()),
+        None => Ok(None),
+    };
+
+    match identified {
+        Ok(Some(identity)) => {
+            req.extensions_mut().insert(identity);
+        }
+        Ok(None) => {}
+        Err(e) => return Ok(req.error_response(e).map_into_right_body()),
+    }
+
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+/// The authenticated user of the request, if any.
+pub fn identity(req: &HttpRequest) -> Option<Identity> {
+    req.extensions().get::<Identity>().cloned()
+}
+
+/// The authenticated user of the request, who must have at least `role`.
+pub fn require(req: &HttpRequest, role: Role) -> Result<Identity, ApiError> {
+    match identity(req) {
+        None => Err(ApiError::unauthorized("Sign in required")),
+        Some(identity) if identity.role < role => Err(ApiError::forbidden(format!(
+            "{} role required, {} is {}",
+            role, identity.name, identity.role
+        ))),
+        Some(identity) => Ok(identity),
+    }
+}
+
+/// Unpublished posts the request may see: all of them when signed in, otherwise
+/// the one a preview `token` was issued for.
+pub fn preview<'a>(req: &HttpRequest, token: Option<&'a str>) -> Preview<'a> {
+    match (identity(req), token) {
+        (Some(_), _) => Preview::All,
+        (None, Some(token)) => Preview::Token(token),
+        (None, None) => Preview::None,
+    }
+}
+
+/// The token, possibly empty, of a `Bearer` authorization header. The scheme is
+/// case-insensitive.
+fn bearer_token(header: &str) -> Option<&str> {
+    let header = header.trim();
+    let (scheme, token) = header.split_once(' ').unwrap_or((header, ""));
+    scheme.eq_ignore_ascii_case("bearer").then(|| token.trim())
+}
+
+fn token_mac(token: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(b"md_to_html api token")
+        .expect("HMAC accepts keys of any length");
+    mac.update(token.as_bytes());
+    mac
+}
+
+fn mac(secret: &str, name: &str, expires: i64) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(format!("{}\n{}", name, expires).as_bytes());
+    mac
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn auth(session_ttl_hours: u64) -> Auth {
+        Auth::new(AuthConfig {
+            tokens: vec![TokenConfig {
+                name: "ada".to_string(),
+                token: "s3cret-token".to_string(),
+                role: Role::Author,
+            }],
+            session_secret: Some("key".to_string()),
+            session_ttl_hours,
+            ..AuthConfig::default()
+        })
+    }
+
+    fn identify(auth: &Auth, authorization: &str) -> Result<Option<Identity>, ApiError> {
+        let req = TestRequest::default()
+            .insert_header((AUTHORIZATION, authorization))
+            .to_http_request();
+        auth.identify(&req)
+    }
+
+    #[test]
+    fn bearer_tokens_identify_their_owner() {
+        let auth = auth(1);
+
+        let identity = identify(&auth, "Bearer s3cret-token").unwrap().unwrap();
+        assert_eq!(identity.name, "ada");
+        assert_eq!(identity.role, Role::Author);
+        assert!(identify(&auth, "bearer  s3cret-token ").unwrap().is_some());
+
+        for wrong in ["Bearer s3cret", "Bearer s3cret-token-2", "Bearer "] {
+            assert!(identify(&auth, wrong).is_err(), "{}", wrong);
+        }
+    }
+
+    #[test]
+    fn other_authorization_schemes_are_ignored() {
+        let auth = auth(1);
+
+        assert!(identify(&auth, "Basic YWRhOnB3").unwrap().is_none());
+        assert!(identify(&auth, "s3cret-token").unwrap().is_none());
+    }
+
+    #[test]
+    fn huge_session_lifetimes_are_clamped() {
+        let auth = auth(u64::MAX);
+        let identity = identify(&auth, "Bearer s3cret-token").unwrap().unwrap();
+
+        let cookie = auth.session_cookie(&identity).unwrap();
+        assert_eq!(
+            cookie.max_age(),
+            Some(CookieDuration::hours(MAX_SESSION_TTL_HOURS as i64))
+        );
+        let session = auth.verify_session(cookie.value(), Utc::now().timestamp());
+        assert_eq!(session.unwrap().name, "ada");
+    }
+}
@@ -0,0 +1,240 @@
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    sync::Mutex,
+};
+
+use flate2::{write::GzEncoder, Compression};
+use log::{debug, error};
+use serde::Deserialize;
+
+// Pages are compressed while the request waits, exports have time to spare
+const BROTLI_RESPONSE_QUALITY: u32 = 5;
+const BROTLI_EXPORT_QUALITY: u32 = 11;
+const BROTLI_WINDOW: u32 = 22;
+
+/// Entries kept in a `CompressionCache` before it is cleared
+const MAX_CACHE_ENTRIES: usize = 512;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CompressionConfig {
+    /// Compress responses with brotli or gzip when the client accepts it, and
+    /// write pre-compressed `.br`/`.gz` files in static export
+    pub enabled: bool,
+    /// Smaller bodies are sent as-is
+    pub min_size: usize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            enabled: true,
+            min_size: 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    pub const ALL: [Encoding; 2] = [Encoding::Brotli, Encoding::Gzip];
+
+    /// Value of the `Content-Encoding` header
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// Extension of pre-compressed files
+    pub fn extension(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gz",
+        }
+    }
+
+    /// Picks the encoding with the highest `q` value in an `Accept-Encoding` header,
+    /// brotli on ties. Codings not listed are only accepted through `*`.
+    pub fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+        let accepted: Vec<(&str, f32)> = accept_encoding
+            .split(',')
+            .map(|item| {
+                let mut parts = item.split(';').map(str::trim);
+                let coding = parts.next().unwrap_or_default();
+                let q = parts
+                    .find_map(|param| {
+                        let (name, value) = param.split_once('=')?;
+                        name.trim().eq_ignore_ascii_case("q").then(|| value.trim())
+                    })
+                    .map_or(Some(1.0), |q| q.parse::<f32>().ok())
+                    .unwrap_or(0.0);
+                (coding, q)
+            })
+            .collect();
+
+        let quality = |encoding: Encoding| {
+            accepted
+                .iter()
+                .find(|(coding, _)| coding.eq_ignore_ascii_case(encoding.name()))
+                .or_else(|| accepted.iter().find(|(coding, _)| *coding == "*"))
+                .map_or(0.0, |(_, q)| *q)
+        };
+
+        // `max_by` keeps the last of equal elements, so go from least to most preferred
+        Encoding::ALL
+            .into_iter()
+            .rev()
+            .map(|encoding| (encoding, quality(encoding)))
+            .filter(|(_, q)| *q > 0.0)
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(encoding, _)| encoding)
+    }
+
+    /// Compresses a response body, trading some size for speed.
+    pub fn compress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        self.compress_with(data, BROTLI_RESPONSE_QUALITY, Compression::default())
+    }
+
+    /// Compresses as small as possible, for files written once.
+    pub fn compress_best(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        self.compress_with(data, BROTLI_EXPORT_QUALITY, Compression::best())
+    }
+
+    fn compress_with(
+        self,
+        data: &[u8],
+        brotli_quality: u32,
+        gzip_level: Compression,
+    ) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let mut output = Vec::new();
+                {
+                    let mut writer = brotli::CompressorWriter::new(
+                        &mut output,
+                        4096,
+                        brotli_quality,
+                        BROTLI_WINDOW,
+                    );
+                    writer.write_all(data)?;
+                }
+                Ok(output)
+            }
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), gzip_level);
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Compressed bodies keyed by a hash of the uncompressed content, shared across
+/// requests so unchanged pages are only compressed once per encoding.
+pub struct CompressionCache {
+    config: CompressionConfig,
+    entries: Mutex<HashMap<(String, Encoding), Vec<u8>>>,
+}
+
+impl CompressionCache {
+    pub fn new(config: CompressionConfig) -> Self {
+        CompressionCache {
+            config,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    /// The encoding `compress` picks for a body of `len` bytes, if any.
+    pub fn encoding(&self, accept_encoding: &str, len: usize) -> Option<Encoding> {
+        if !self.config.enabled || len < self.config.min_size {
+            return None;
+        }
+        Encoding::negotiate(accept_encoding)
+    }
+
+    /// Compresses `data` (identified by `hash`) with the best encoding the client
+    /// accepts, or returns `None` if it should be sent uncompressed.
+    pub fn compress(
+        &self,
+        accept_encoding: &str,
+        hash: &str,
+        data: &[u8],
+    ) -> Option<(Encoding, Vec<u8>)> {
+        let encoding = self.encoding(accept_encoding, data.len())?;
+        let key = (hash.to_string(), encoding);
+        if let Some(body) = self.entries.lock().ok().and_then(|e| e.get(&key).cloned()) {
+            debug!("Compression cache hit: {} ({})", hash, encoding.name());
+            return Some((encoding, body));
+        }
+
+        let body = match encoding.compress(data) {
+            Ok(body) => body,
+            Err(e) => {
+                error!("Failed to compress with {}: {}", encoding.name(), e);
+                return None;
+            }
+        };
+
+        if let Ok(mut entries) = self.entries.lock() {
+            // Old revisions of pages are never requested again, start over instead of tracking usage
+            if entries.len() >= MAX_CACHE_ENTRIES {
+                entries.clear();
+            }
+            entries.insert(key, body.clone());
+        }
+
+        Some((encoding, body))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefers_brotli_when_both_are_equally_acceptable() {
+        assert_eq!(
+            Encoding::negotiate("gzip, deflate, br"),
+            Some(Encoding::Brotli)
+        );
+        assert_eq!(
+            Encoding::negotiate("br;q=0.8, gzip;q=0.8"),
+            Some(Encoding::Brotli)
+        );
+        assert_eq!(Encoding::negotiate("*"), Some(Encoding::Brotli));
+    }
+
+    #[test]
+    fn honours_q_values() {
+        assert_eq!(
+            Encoding::negotiate("gzip;q=1, br;q=0.1"),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(Encoding::negotiate("br;q=0, gzip"), Some(Encoding::Gzip));
+        assert_eq!(
+            Encoding::negotiate("br; Q=0.5, gzip;q=0.9"),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(Encoding::negotiate("*;q=0.5, br;q=0"), Some(Encoding::Gzip));
+        assert_eq!(Encoding::negotiate("br;q=0, gzip;q=0"), None);
+        assert_eq!(Encoding::negotiate("*;q=0"), None);
+    }
+
+    #[test]
+    fn ignores_unlisted_and_malformed_codings() {
+        assert_eq!(Encoding::negotiate(""), None);
+        assert_eq!(Encoding::negotiate("identity, deflate"), None);
+        assert_eq!(Encoding::negotiate("br;q=abc, gzip"), Some(Encoding::Gzip));
+    }
+}
@@ -0,0 +1,117 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use log::info;
+use serde::Deserialize;
+
+use crate::auth::AuthConfig;
+use crate::compression::CompressionConfig;
+use crate::renderer::RenderOptions;
+
+const DEFAULT_CONFIG_PATH: &str = "config.json";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub base_path: PathBuf,
+    pub template_path: PathBuf,
+    /// Directory served under `/static`
+    pub static_dir: PathBuf,
+    pub renderer: RenderOptions,
+    pub cache_control: CacheControlConfig,
+    pub compression: CompressionConfig,
+    pub api: ApiConfig,
+    pub auth: AuthConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ApiConfig {
+    /// Largest markdown body accepted by `POST /api/render`
+    pub render_max_bytes: usize,
+    /// Time allowed for rendering submitted markdown
+    pub render_timeout_ms: u64,
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        ApiConfig {
+            render_max_bytes: 256 * 1024,
+            render_timeout_ms: 5000,
+        }
+    }
+}
+
+/// `Cache-Control` header sent for each kind of route.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CacheControlConfig {
+    /// Rendered posts, revalidated with their ETag by default
+    pub pages: String,
+    /// Files stored next to the posts
+    pub assets: String,
+    /// Generated image variants, whose names change with their source
+    pub image_variants: String,
+    /// Files under `/static`
+    pub static_files: String,
+}
+
+impl Default for CacheControlConfig {
+    fn default() -> Self {
+        CacheControlConfig {
+            pages: "public, max-age=0, must-revalidate".to_string(),
+            assets: "public, max-age=3600".to_string(),
+            image_variants: "public, max-age=31536000, immutable".to_string(),
+            static_files: "public, max-age=3600".to_string(),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            base_path: PathBuf::from("blog"),
+            template_path: PathBuf::from("template.html"),
+            static_dir: PathBuf::from("static"),
+            renderer: RenderOptions::default(),
+            cache_control: CacheControlConfig::default(),
+            compression: CompressionConfig::default(),
+            api: ApiConfig::default(),
+            auth: AuthConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Loads the config from `$BLOG_CONFIG` (or `config.json`), falling back to
+    /// defaults when the file does not exist.
+    pub fn load() -> std::io::Result<Self> {
+        let path = env::var("BLOG_CONFIG").unwrap_or_else(|_| DEFAULT_CONFIG_PATH.to_string());
+        let path = Path::new(&path);
+
+        if !path.exists() {
+            info!("No config file at {}, using defaults", path.display());
+            return Ok(Config::default());
+        }
+
+        let content = fs::read_to_string(path)?;
+        let mut config: Config = serde_json::from_str(&content)
+            .map_err(|e| std::io::Error::other(format!("{}: {}", path.display(), e)))?;
+
+        // Custom callout kinds extend the built-in ones instead of replacing them
+        let mut callouts = RenderOptions::default().callouts;
+        callouts.extend(
+            config
+                .renderer
+                .callouts
+                .drain()
+                .map(|(kind, style)| (kind.to_lowercase(), style)),
+        );
+        config.renderer.callouts = callouts;
+
+        info!("Loaded config from {}", path.display());
+        Ok(config)
+    }
+}
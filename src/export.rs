@@ -0,0 +1,348 @@
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+use log::info;
+
+use crate::apierror::ApiError;
+use crate::compression::{CompressionConfig, Encoding};
+use crate::config::Config;
+use crate::renderer::{
+    error::RendererError, escape_html, posts::Redirect, search, Renderer, BLOG_URL_PREFIX,
+};
+
+/// Extensions of exported files worth compressing
+const COMPRESSED_EXTENSIONS: &[&str] = &["html", "css", "js", "json", "xml", "svg", "txt"];
+
+/// Renders every post into `out_dir` as static files, laid out like the server's URLs
+/// (`/blog/my-post` becomes `blog/my-post/index.html`), together with post assets,
+/// generated image variants, the static directory, redirect stubs, series pages, a
+/// `404.html` and a search page backed by a JSON index.
+/// Text files also get `.br` and `.gz` siblings for servers that serve them directly.
+pub fn export(renderer: &Renderer, config: &Config, out_dir: &Path) -> Result<(), ApiError> {
+    let posts = renderer.posts()?;
+    for post in &posts {
+        let html = renderer.render(post)?;
+        write_file(&output_path(out_dir, &post.href())?, html.as_bytes())?;
+    }
+
+    // Without a server, the search page searches a prebuilt index in the browser
+    let documents = renderer.search_documents()?;
+    let index =
+        serde_json::to_vec(&search::static_index(&documents)).map_err(ApiError::internal_error)?;
+    let search_dir = out_dir.join(page_dir(BLOG_URL_PREFIX)).join("search");
+    write_file(&search_dir.join("index.json"), &index)?;
+    write_file(&search_dir.join("search.js"), search::WIDGET_JS.as_bytes())?;
+    let search_page = renderer.render_page(
+        "Search",
+        search::static_search_page_html(
+            &format!("{}/search/index.json", BLOG_URL_PREFIX),
+            &format!("{}/search/search.js", BLOG_URL_PREFIX),
+        ),
+    )?;
+    write_file(&search_dir.join("index.html"), search_page.as_bytes())?;
+
+    let series = renderer.all_series()?;
+    for series in &series {
+        let html = renderer.render_page(&series.name, series.page_html())?;
+        write_file(&output_path(out_dir, &series.href())?, html.as_bytes())?;
+    }
+
+    // Most static hosts serve this for unknown paths
+    let not_found = renderer.render_error_page(404, "Not Found", "", "NOT_FOUND")?;
+    write_file(&out_dir.join("404.html"), not_found.as_bytes())?;
+
+    let redirects = renderer.redirects()?;
+    for (from, redirect) in &redirects {
+        let from = format!("{}/{}", BLOG_URL_PREFIX, from);
+        write_file(
+            &output_path(out_dir, &from)?,
+            redirect_stub(redirect).as_bytes(),
+        )?;
+    }
+
+    // Everything but the posts themselves, e.g. images referenced by relative links
+    let redirects_file = config
+        .base_path
+        .join(&config.renderer.permalinks.redirects_file);
+    copy_dir(
+        &config.base_path,
+        &out_dir.join(page_dir(BLOG_URL_PREFIX)),
+        &|path| path.extension().is_some_and(|ext| ext == "md") || path == redirects_file,
+    )?;
+
+    // Variants are generated while rendering, so the cache is complete by now
+    let images = &config.renderer.images;
+    if images.enabled && images.url_prefix.starts_with('/') {
+        copy_dir(
+            &images.cache_dir,
+            &out_dir.join(page_dir(&images.url_prefix)),
+            &|_| false,
+        )?;
+    }
+    copy_dir(&config.static_dir, &out_dir.join("static"), &|_| false)?;
+
+    if config.compression.enabled {
+        precompress(out_dir, &config.compression)?;
+    }
+
+    info!(
+        "Exported {} posts ({} searchable) and {} redirects to {}",
+        posts.len(),
+        documents.len(),
+        redirects.len(),
+        out_dir.display()
+    );
+    Ok(())
+}
+
+/// Output file for a URL inside `out_dir`. URLs that would leave it, e.g. through
+/// `..` segments, are rejected.
+fn output_path(out_dir: &Path, url: &str) -> Result<PathBuf, RendererError> {
+    let file = page_file(url);
+    if !file
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err(RendererError::InvalidPathError(url.to_string()));
+    }
+
+    Ok(out_dir.join(file))
+}
+
+/// Output file for a URL: directory-style URLs get an `index.html`, URLs of HTML
+/// files (e.g. an old `post.html` alias) are written as-is.
+fn page_file(url: &str) -> PathBuf {
+    let dir = page_dir(url);
+    if url.ends_with(".html") || url.ends_with(".htm") {
+        dir
+    } else {
+        dir.join("index.html")
+    }
+}
+
+fn page_dir(url: &str) -> PathBuf {
+    PathBuf::from(url.trim_matches('/'))
+}
+
+/// Page that sends browsers and crawlers on to the redirect target.
+fn redirect_stub(redirect: &Redirect) -> String {
+    let to = escape_html(&redirect.to);
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <title>Redirecting…</title>
+    <link rel="canonical" href="{to}">
+    <meta name="robots" content="noindex">
+    <meta http-equiv="refresh" content="0; url={to}">
+</head>
+<body>
+    <p>This page has moved to <a href="{to}">{to}</a>.</p>
+</body>
+</html>
+"#,
+        to = to
+    )
+}
+
+/// Writes compressed copies next to every text file under `dir`.
+fn precompress(dir: &Path, config: &CompressionConfig) -> Result<(), RendererError> {
+    let entries = fs::read_dir(dir).map_err(|e| RendererError::FileReadError {
+        path: dir.to_string_lossy().to_string(),
+        source: e,
+    })?;
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            precompress(&path, config)?;
+            continue;
+        }
+
+        let is_text = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| COMPRESSED_EXTENSIONS.contains(&ext));
+        if !is_text {
+            continue;
+        }
+
+        let content = fs::read(&path).map_err(|e| RendererError::FileReadError {
+            path: path.to_string_lossy().to_string(),
+            source: e,
+        })?;
+        if content.len() < config.min_size {
+            continue;
+        }
+
+        for encoding in Encoding::ALL {
+            let mut target = path.clone().into_os_string();
+            target.push(".");
+            target.push(encoding.extension());
+            let target = PathBuf::from(target);
+
+            let compressed =
+                encoding
+                    .compress_best(&content)
+                    .map_err(|e| RendererError::FileWriteError {
+                        path: target.to_string_lossy().to_string(),
+                        source: e,
+                    })?;
+            write_file(&target, &compressed)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn write_file(path: &Path, content: &[u8]) -> Result<(), RendererError> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| RendererError::FileWriteError {
+            path: parent.to_string_lossy().to_string(),
+            source: e,
+        })?;
+    }
+
+    fs::write(path, content).map_err(|e| RendererError::FileWriteError {
+        path: path.to_string_lossy().to_string(),
+        source: e,
+    })
+}
+
+/// Recursively copies `from` into `to`, skipping hidden entries and files matching `skip`.
+/// A missing source directory is not an error.
+fn copy_dir(from: &Path, to: &Path, skip: &dyn Fn(&Path) -> bool) -> Result<(), RendererError> {
+    if !from.is_dir() {
+        return Ok(());
+    }
+
+    let entries = fs::read_dir(from).map_err(|e| RendererError::FileReadError {
+        path: from.to_string_lossy().to_string(),
+        source: e,
+    })?;
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if entry.file_name().to_string_lossy().starts_with('.') || skip(&path) {
+            continue;
+        }
+
+        let target = to.join(entry.file_name());
+        if path.is_dir() {
+            copy_dir(&path, &target, skip)?;
+        } else {
+            let content = fs::read(&path).map_err(|e| RendererError::FileReadError {
+                path: path.to_string_lossy().to_string(),
+                source: e,
+            })?;
+            write_file(&target, &content)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::renderer::fixtures::fixture;
+
+    /// Exports the fixture blog `name` with `posts`, returning the output directory.
+    fn export_fixture(name: &str, posts: &[(&str, &str)]) -> PathBuf {
+        let renderer = fixture(name, posts);
+        let dir = std::env::temp_dir().join(format!(
+            "md_to_html_renderer_{}_{}",
+            std::process::id(),
+            name
+        ));
+        let config = Config {
+            base_path: dir.join("blog"),
+            template_path: dir.join("template.html"),
+            static_dir: dir.join("static"),
+            ..Config::default()
+        };
+        let out_dir = dir.join("dist");
+        export(&renderer, &config, &out_dir).unwrap();
+        out_dir
+    }
+
+    #[test]
+    fn output_paths_stay_inside_the_output_directory() {
+        let out_dir = Path::new("/tmp/out");
+
+        assert_eq!(
+            output_path(out_dir, "/blog/post").unwrap(),
+            out_dir.join("blog/post/index.html")
+        );
+        assert_eq!(
+            output_path(out_dir, "/blog/old.html").unwrap(),
+            out_dir.join("blog/old.html")
+        );
+        for url in ["/blog/../../x", "/blog/a/../../../etc", "/blog/a/.."] {
+            assert!(output_path(out_dir, url).is_err(), "{}", url);
+        }
+    }
+
+    #[test]
+    fn search_index_lists_searchable_posts() {
+        let out_dir = export_fixture(
+            "export_search_index",
+            &[
+                (
+                    "post.md",
+                    "<!--\ntitle: Otters & Seals\ndate: 2024-01-02\ntags: animals, sea\n-->\n# Otters & Seals\n\nSeals sleep & seals swim.\n\n## Diet\n\nFish.\n",
+                ),
+                ("draft.md", "<!--\ndraft: true\n-->\n# Draft\n\nHidden\n"),
+                ("unlisted.md", "<!--\nunlisted: true\n-->\n# Unlisted\n\nHidden\n"),
+            ],
+        );
+
+        let index: serde_json::Value =
+            serde_json::from_slice(&fs::read(out_dir.join("blog/search/index.json")).unwrap())
+                .unwrap();
+        assert_eq!(
+            index,
+            json!({
+                "documents": [{
+                    "url": "/blog/post",
+                    "title": "Otters & Seals",
+                    "headings": ["Diet"],
+                    "tags": ["animals", "sea"],
+                    "date": "2024-01-02T00:00:00+00:00",
+                    "excerpt": "Seals sleep &amp; seals swim.",
+                    "terms": "diet fish otters seals sleep swim",
+                }]
+            })
+        );
+    }
+
+    #[test]
+    fn search_page_loads_the_widget_and_index() {
+        let out_dir = export_fixture("export_search_page", &[("post.md", "# Post\n")]);
+        let search_dir = out_dir.join("blog/search");
+
+        let page = fs::read_to_string(search_dir.join("index.html")).unwrap();
+        assert!(page.starts_with("<title>Search</title>"), "{}", page);
+        assert!(
+            page.contains(r#"<div data-search-index="/blog/search/index.json">"#),
+            "{}",
+            page
+        );
+        assert!(page.contains("<form"), "{}", page);
+        assert!(page.contains("<div data-search-results></div>"), "{}", page);
+        assert!(
+            page.contains(r#"<script src="/blog/search/search.js"></script>"#),
+            "{}",
+            page
+        );
+        assert_eq!(
+            fs::read_to_string(search_dir.join("search.js")).unwrap(),
+            search::WIDGET_JS
+        );
+    }
+}
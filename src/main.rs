@@ -1,14 +1,26 @@
 #![allow(dead_code)]
 
 use actix_cors::Cors;
-use actix_web::{web, App, HttpServer};
+use actix_web::{
+    middleware::{from_fn, Compress, Condition},
+    web, App, HttpServer,
+};
 use env_logger::Env;
-use log::info;
+use log::{error, info};
+use std::{env, path::PathBuf};
 
 mod api;
 mod apierror;
+mod auth;
+mod compression;
+mod config;
+mod export;
 mod renderer;
 
+use api::routes::PageVersions;
+use auth::Auth;
+use compression::CompressionCache;
+use config::Config;
 use renderer::Renderer;
 
 #[actix_web::main]
@@ -16,11 +28,63 @@ async fn main() -> std::io::Result<()> {
     // Initialize logger
     env_logger::init_from_env(Env::default().default_filter_or("info"));
 
+    let config = Config::load()?;
+
+    let image_prefix = config.renderer.images.url_prefix.clone();
+    let static_dir = config.static_dir.clone();
+    let cache_control = web::Data::new(config.cache_control.clone());
+    let compression_cache = web::Data::new(CompressionCache::new(config.compression.clone()));
+    let page_versions = web::Data::new(PageVersions::default());
+    let compress = config.compression.enabled;
+    let api_config = config.api.clone();
+    let auth = web::Data::new(Auth::new(config.auth.clone()));
+
     let renderer = web::Data::new(
-        Renderer::new("blog", "template.html")
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?,
+        Renderer::new(
+            &config.base_path,
+            &config.template_path,
+            config.renderer.clone(),
+        )
+        .map_err(|e| std::io::Error::other(e.to_string()))?,
     );
 
+    // `md_to_html export [dir]` writes the whole site as static files instead of serving it,
+    // `md_to_html preview <post>` prints a signed preview link for a draft
+    let args: Vec<String> = env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("export") => {
+            let out_dir = PathBuf::from(args.get(2).map_or("dist", String::as_str));
+            return export::export(&renderer, &config, &out_dir)
+                .map_err(|e| std::io::Error::other(e.to_string()));
+        }
+        Some("preview") => {
+            let target = args.get(2).map_or("", String::as_str);
+            let url = renderer
+                .preview_url(target)
+                .map_err(|e| std::io::Error::other(e.to_string()))?;
+            println!("http://localhost:8080{}", url);
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    // Pick up posts changed outside the API, so searches never have to
+    actix_web::rt::spawn({
+        let renderer = renderer.clone();
+        async move {
+            let mut interval = actix_web::rt::time::interval(renderer::POST_WATCH_INTERVAL);
+            loop {
+                interval.tick().await;
+                let renderer = renderer.clone();
+                match web::block(move || renderer.refresh_changed_posts()).await {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => error!("Failed to refresh posts: {}", e),
+                    Err(e) => error!("Failed to refresh posts: {}", e),
+                }
+            }
+        }
+    });
+
     info!("Starting server on http://localhost:8080");
     HttpServer::new(move || {
         // Configure CORS
@@ -31,10 +95,20 @@ async fn main() -> std::io::Result<()> {
             .max_age(3600);
 
         App::new()
+            .wrap(from_fn(auth::authenticate)) // Identify users by token or session cookie
+            .wrap(api::error_pages::error_pages()) // Render HTML error pages for browsers
+            .wrap(Condition::new(compress, Compress::default())) // Compress other responses
             .wrap(cors) // Add CORS middleware
             .wrap(actix_web::middleware::Logger::default()) // Add logger middleware
             .app_data(renderer.clone())
+            .app_data(cache_control.clone())
+            .app_data(compression_cache.clone())
+            .app_data(page_versions.clone())
+            .app_data(auth.clone())
+            .service(api::posts::api_routes(api_config.clone()))
             .service(api::routes::blog_routes())
+            .service(api::routes::image_routes(&image_prefix))
+            .service(api::routes::static_routes(static_dir.clone()))
     })
     .bind("localhost:8080")?
     .run()
@@ -0,0 +1,212 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    panic,
+    sync::Mutex,
+};
+
+use layout::{
+    backends::svg::SVGWriter,
+    gv::{DotParser, GraphBuilder},
+};
+use log::{debug, error};
+
+use super::renderer::escape_html;
+
+/// Info strings of fenced code blocks rendered as Graphviz diagrams
+pub const DIAGRAM_LANGUAGES: &[&str] = &["dot", "graphviz"];
+
+/// Largest diagram source laid out. Layout can't be interrupted, so this bounds
+/// the time a single diagram takes.
+const MAX_DIAGRAM_BYTES: usize = 16 * 1024;
+
+/// Diagrams kept in a `DiagramCache`, the least recently used are evicted first
+const MAX_CACHE_ENTRIES: usize = 256;
+
+/// Rendered diagram SVGs keyed by a hash of their source, shared across requests.
+#[derive(Default)]
+pub struct DiagramCache {
+    entries: Mutex<CacheEntries>,
+}
+
+#[derive(Default)]
+struct CacheEntries {
+    /// Rendered HTML and when it was last used
+    diagrams: HashMap<u64, (String, u64)>,
+    clock: u64,
+}
+
+impl DiagramCache {
+    /// Renders a DOT diagram to inline SVG markup. Syntax errors are rendered as an
+    /// inline error block instead of failing the page.
+    pub fn render(&self, source: &str) -> String {
+        let mut hasher = DefaultHasher::new();
+        source.hash(&mut hasher);
+        let key = hasher.finish();
+
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.clock += 1;
+            let clock = entries.clock;
+            if let Some((html, used)) = entries.diagrams.get_mut(&key) {
+                debug!("Diagram cache hit: {:x}", key);
+                *used = clock;
+                return html.clone();
+            }
+        }
+
+        let html = match render_dot(source) {
+            // SVG ids are document-wide, so each diagram gets its own
+            Ok(svg) => format!(
+                "<figure class=\"diagram my-6 p-4 bg-gruvbox-fg rounded-lg overflow-x-auto flex justify-center\">{}</figure>\n",
+                prefix_ids(&svg, &format!("diagram-{:x}-", key))
+            ),
+            Err(e) => format!(
+                "<div class=\"diagram-error my-6 p-4 border border-gruvbox-red rounded-lg\">\n<p class=\"text-gruvbox-red mb-2\">Diagram error: {}</p>\n<pre><code class=\"language-dot\">{}</code></pre>\n</div>\n",
+                escape_html(&e),
+                escape_html(source)
+            ),
+        };
+
+        if let Ok(mut entries) = self.entries.lock() {
+            if entries.diagrams.len() >= MAX_CACHE_ENTRIES {
+                let oldest = entries
+                    .diagrams
+                    .iter()
+                    .min_by_key(|(_, (_, used))| *used)
+                    .map(|(key, _)| *key);
+                if let Some(oldest) = oldest {
+                    entries.diagrams.remove(&oldest);
+                }
+            }
+            let clock = entries.clock;
+            entries.diagrams.insert(key, (html.clone(), clock));
+        }
+
+        html
+    }
+}
+
+fn render_dot(source: &str) -> Result<String, String> {
+    if source.len() > MAX_DIAGRAM_BYTES {
+        return Err(format!(
+            "diagrams are limited to {} bytes",
+            MAX_DIAGRAM_BYTES
+        ));
+    }
+
+    // The parser and layout engine panic on some malformed graphs, keep that from
+    // taking the request down
+    panic::catch_unwind(|| {
+        let mut parser = DotParser::new(source);
+        let graph = parser
+            .process()
+            .map_err(|e| describe_parse_error(&e, source, &parser))?;
+
+        let mut builder = GraphBuilder::new();
+        builder.visit_graph(&graph);
+        let mut visual_graph = builder.get();
+
+        let mut writer = SVGWriter::new();
+        visual_graph.do_it(false, false, false, &mut writer);
+        Ok(writer.finalize())
+    })
+    .map_err(|_| {
+        error!("Diagram layout failed");
+        "the diagram could not be laid out".to_string()
+    })?
+    .map(|svg| match svg.find("<svg") {
+        // Drop the XML prolog so the SVG can be inlined
+        Some(start) => svg[start..].to_string(),
+        None => svg,
+    })
+}
+
+/// Adds the line and column the parser stopped at to its error message.
+fn describe_parse_error(message: &str, source: &str, parser: &DotParser) -> String {
+    let message = match message {
+        "port" => "Expected a node name",
+        message => message.trim(),
+    };
+
+    // The parser only exposes its position through `Debug`
+    let debug = format!("{:?}", parser);
+    let position = debug.find("], pos: ").and_then(|start| {
+        let digits = &debug[start + 8..];
+        let end = digits.find(|c: char| !c.is_ascii_digit())?;
+        digits[..end].parse::<usize>().ok()
+    });
+    let Some(position) = position else {
+        return message.to_string();
+    };
+
+    // The position is one past the character that stopped the parser
+    let consumed: Vec<char> = source.chars().take(position.saturating_sub(1)).collect();
+    let line = consumed.iter().filter(|c| **c == '\n').count() + 1;
+    let column = consumed.iter().rev().take_while(|c| **c != '\n').count() + 1;
+    format!("{} near line {}, column {}", message, line, column)
+}
+
+/// Prefixes the ids in an SVG and the references to them.
+fn prefix_ids(svg: &str, prefix: &str) -> String {
+    svg.replace("id=\"", &format!("id=\"{}", prefix))
+        .replace("url(#", &format!("url(#{}", prefix))
+        .replace("href=\"#", &format!("href=\"#{}", prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(html: &str) -> Vec<&str> {
+        html.split(" id=\"")
+            .skip(1)
+            .filter_map(|rest| rest.split('"').next())
+            .collect()
+    }
+
+    #[test]
+    fn diagrams_have_distinct_ids() {
+        let cache = DiagramCache::default();
+
+        let first = cache.render("digraph { a -> b [label=\"x\"] }");
+        let second = cache.render("digraph { c -> d [label=\"y\"] }");
+        assert!(!ids(&first).is_empty(), "{}", first);
+        for id in ids(&first) {
+            assert!(!ids(&second).contains(&id), "{} is in both diagrams", id);
+        }
+        assert!(!first.contains("url(#endarrow)"));
+    }
+
+    #[test]
+    fn references_point_into_the_same_diagram() {
+        let html = DiagramCache::default().render("digraph { a -> b [label=\"x\"] }");
+
+        for reference in html
+            .split("url(#")
+            .skip(1)
+            .chain(html.split("href=\"#").skip(1))
+        {
+            let id = reference.split([')', '"']).next().unwrap();
+            assert!(ids(&html).contains(&id), "{} is not defined", id);
+        }
+    }
+
+    #[test]
+    fn syntax_errors_include_the_position() {
+        let html = DiagramCache::default().render("digraph {\n  a -> ;\n}");
+
+        assert!(html.contains("diagram-error"), "{}", html);
+        assert!(html.contains("near line 2, column"), "{}", html);
+    }
+
+    #[test]
+    fn cache_is_bounded() {
+        let cache = DiagramCache::default();
+
+        for i in 0..MAX_CACHE_ENTRIES + 10 {
+            cache.render(&format!("digraph {{ a{} }}", i));
+        }
+        let entries = cache.entries.lock().unwrap();
+        assert_eq!(entries.diagrams.len(), MAX_CACHE_ENTRIES);
+    }
+}
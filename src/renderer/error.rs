@@ -1,6 +1,7 @@
 use std::io;
 use thiserror::Error;
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Error, Debug)]
 pub enum RendererError {
     #[error("Failed to read file: {path}")]
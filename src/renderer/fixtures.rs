@@ -0,0 +1,25 @@
+//! Blogs in temporary directories, for tests.
+
+use std::{env, fs, process};
+
+use super::{RenderOptions, Renderer};
+
+/// Creates `<tmp>/<name>/blog` holding `posts` (file name, source) and a template,
+/// returning a renderer for it.
+pub fn fixture(name: &str, posts: &[(&str, &str)]) -> Renderer {
+    fixture_with(name, posts, RenderOptions::default())
+}
+
+pub fn fixture_with(name: &str, posts: &[(&str, &str)], options: RenderOptions) -> Renderer {
+    let dir = env::temp_dir().join(format!("md_to_html_renderer_{}_{}", process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("blog")).unwrap();
+    for (file_name, source) in posts {
+        let path = dir.join("blog").join(file_name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, source).unwrap();
+    }
+    fs::write(dir.join("template.html"), "<title>{title}</title>{content}").unwrap();
+
+    Renderer::new(dir.join("blog"), dir.join("template.html"), options).unwrap()
+}
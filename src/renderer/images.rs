@@ -0,0 +1,309 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    fs,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::UNIX_EPOCH,
+};
+
+use image::{imageops::FilterType, DynamicImage, ImageFormat};
+use log::{debug, error, info};
+use percent_encoding::percent_decode_str;
+
+use super::{options::ResponsiveImageOptions, posts::slugify};
+
+/// Sizes and URLs of the generated variants of a local image.
+pub struct ResponsiveImage {
+    pub width: u32,
+    pub height: u32,
+    pub src: String,
+    pub srcset: String,
+    pub webp_srcset: String,
+    pub sizes: String,
+}
+
+/// Resizes images stored under the blog directory into several widths and formats,
+/// caching the variants on disk so each one is only generated once per source change.
+pub struct ImageProcessor {
+    base_path: PathBuf,
+    options: ResponsiveImageOptions,
+    // Serializes variant generation so concurrent requests don't encode the same file
+    lock: Mutex<()>,
+}
+
+impl ImageProcessor {
+    pub fn new(base_path: &Path, options: ResponsiveImageOptions) -> Self {
+        ImageProcessor {
+            base_path: base_path.to_path_buf(),
+            options,
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub fn cache_dir(&self) -> &Path {
+        &self.options.cache_dir
+    }
+
+    /// Returns the responsive variants for an image referenced from a post in
+    /// `post_dir`, or `None` for remote, missing or undecodable images. GIFs are
+    /// left alone, resizing them would only keep their first frame.
+    pub fn process(&self, url: &str, post_dir: &Path) -> Option<ResponsiveImage> {
+        if !self.options.enabled || url.is_empty() || url.contains("://") || url.starts_with("//") {
+            return None;
+        }
+
+        let source_path = self.resolve_source(url, post_dir)?;
+        let format = ImageFormat::from_path(&source_path).ok()?;
+        if !matches!(
+            format,
+            ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::WebP
+        ) {
+            return None;
+        }
+
+        let (width, height) = match image::image_dimensions(&source_path) {
+            Ok(dimensions) => dimensions,
+            Err(e) => {
+                debug!("Cannot read image {:?}: {}", source_path, e);
+                return None;
+            }
+        };
+
+        let key = self.cache_key(&source_path)?;
+        // Variant URLs end up in `srcset`, which is split on whitespace and commas
+        let stem = match slugify(&source_path.file_stem()?.to_string_lossy()) {
+            stem if stem.is_empty() => "image".to_string(),
+            stem => stem,
+        };
+        let extension = format.extensions_str().first()?.to_string();
+
+        let mut widths: Vec<u32> = self
+            .options
+            .widths
+            .iter()
+            .copied()
+            .filter(|w| *w < width)
+            .collect();
+        widths.push(width);
+        widths.sort_unstable();
+        widths.dedup();
+
+        self.generate_variants(&source_path, &stem, &key, &extension, &widths)?;
+
+        let srcset_for = |ext: &str| {
+            widths
+                .iter()
+                .map(|w| format!("{} {}w", self.variant_url(&stem, &key, *w, ext), w))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+
+        Some(ResponsiveImage {
+            width,
+            height,
+            src: self.variant_url(&stem, &key, width, &extension),
+            srcset: srcset_for(&extension),
+            webp_srcset: srcset_for("webp"),
+            sizes: self.options.sizes.clone(),
+        })
+    }
+
+    /// Resolves an image URL relative to the post, rejecting anything outside `base_path`.
+    fn resolve_source(&self, url: &str, post_dir: &Path) -> Option<PathBuf> {
+        let path = url.split(['?', '#']).next()?;
+        let relative = percent_decode_str(path).decode_utf8().ok()?;
+        let candidate = match relative.strip_prefix('/') {
+            Some(absolute) => self.base_path.join(absolute.trim_start_matches("blog/")),
+            None => post_dir.join(&*relative),
+        };
+
+        let canonical = candidate.canonicalize().ok()?;
+        let base = self.base_path.canonicalize().ok()?;
+        if !canonical.starts_with(&base) {
+            debug!("Image outside of blog directory: {:?}", candidate);
+            return None;
+        }
+
+        Some(canonical)
+    }
+
+    fn cache_key(&self, source_path: &Path) -> Option<String> {
+        let metadata = fs::metadata(source_path).ok()?;
+        let modified = metadata
+            .modified()
+            .ok()?
+            .duration_since(UNIX_EPOCH)
+            .ok()?
+            .as_secs();
+
+        let mut hasher = DefaultHasher::new();
+        source_path.hash(&mut hasher);
+        metadata.len().hash(&mut hasher);
+        modified.hash(&mut hasher);
+        Some(format!("{:08x}", hasher.finish() as u32))
+    }
+
+    fn variant_name(stem: &str, key: &str, width: u32, extension: &str) -> String {
+        format!("{}-{}-{}.{}", stem, key, width, extension)
+    }
+
+    fn variant_url(&self, stem: &str, key: &str, width: u32, extension: &str) -> String {
+        format!(
+            "{}/{}",
+            self.options.url_prefix.trim_end_matches('/'),
+            Self::variant_name(stem, key, width, extension)
+        )
+    }
+
+    fn generate_variants(
+        &self,
+        source_path: &Path,
+        stem: &str,
+        key: &str,
+        extension: &str,
+        widths: &[u32],
+    ) -> Option<()> {
+        let cache_dir = &self.options.cache_dir;
+        let pending: Vec<(u32, &str)> = widths
+            .iter()
+            .flat_map(|w| [(*w, extension), (*w, "webp")])
+            .filter(|(w, ext)| {
+                !cache_dir
+                    .join(Self::variant_name(stem, key, *w, ext))
+                    .exists()
+            })
+            .collect();
+
+        if pending.is_empty() {
+            return Some(());
+        }
+
+        let _guard = self.lock.lock().ok()?;
+        fs::create_dir_all(cache_dir)
+            .map_err(|e| error!("Cannot create image cache {:?}: {}", cache_dir, e))
+            .ok()?;
+
+        let original = image::open(source_path)
+            .map_err(|e| error!("Cannot decode image {:?}: {}", source_path, e))
+            .ok()?;
+
+        for (width, ext) in pending {
+            let target = cache_dir.join(Self::variant_name(stem, key, width, ext));
+            if target.exists() {
+                continue;
+            }
+
+            let mut variant = if width < original.width() {
+                original.resize(width, u32::MAX, FilterType::Lanczos3)
+            } else {
+                original.clone()
+            };
+            // The WebP encoder only accepts 8-bit buffers
+            if ext == "webp" {
+                variant = DynamicImage::ImageRgba8(variant.to_rgba8());
+            }
+
+            // Write next to the target and rename, so a half-written file is never served
+            let temp = target.with_extension(format!("{}.tmp", ext));
+            let format = ImageFormat::from_extension(ext)?;
+            let result = variant
+                .save_with_format(&temp, format)
+                .map_err(|e| e.to_string())
+                .and_then(|_| fs::rename(&temp, &target).map_err(|e| e.to_string()));
+
+            match result {
+                Ok(()) => info!("Generated image variant {:?}", target),
+                Err(e) => {
+                    error!("Cannot write image variant {:?}: {}", target, e);
+                    let _ = fs::remove_file(&temp);
+                    return None;
+                }
+            }
+        }
+
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use image::{ImageBuffer, Rgba};
+
+    use super::*;
+
+    /// An image processor for `<tmp>/<name>/blog` holding 600px wide images named
+    /// `files`, caching variants at 300px and full width.
+    fn fixture(name: &str, files: &[&str]) -> (ImageProcessor, PathBuf) {
+        let dir = env::temp_dir().join(format!("md_to_html_images_{}_{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        let blog = dir.join("blog");
+        fs::create_dir_all(&blog).unwrap();
+        for file in files {
+            ImageBuffer::from_pixel(600, 20, Rgba([200u8, 100, 0, 255]))
+                .save(blog.join(file))
+                .unwrap();
+        }
+
+        let options = ResponsiveImageOptions {
+            widths: vec![300],
+            cache_dir: dir.join("cache"),
+            ..ResponsiveImageOptions::default()
+        };
+        (ImageProcessor::new(&blog, options), blog)
+    }
+
+    /// The URLs of a `srcset`, checking each candidate is a URL and a width.
+    fn srcset_urls(srcset: &str) -> Vec<&str> {
+        srcset
+            .split(", ")
+            .map(|candidate| {
+                let parts: Vec<&str> = candidate.split_whitespace().collect();
+                assert_eq!(parts.len(), 2, "{}", srcset);
+                assert!(!parts[0].contains(','), "{}", srcset);
+                parts[0]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn variant_urls_are_safe_in_srcset() {
+        let (processor, blog) = fixture("names", &["my shot.png", "a,b.png"]);
+
+        let image = processor.process("my shot.png", &blog).unwrap();
+        let urls = srcset_urls(&image.srcset);
+        assert_eq!(urls.len(), 2);
+        assert!(urls[0].starts_with("/images/my-shot-"), "{}", urls[0]);
+        assert!(urls[0].ends_with("-300.png"));
+        assert_eq!(urls[1], image.src);
+        assert_eq!(srcset_urls(&image.webp_srcset).len(), 2);
+
+        let image = processor.process("a,b.png", &blog).unwrap();
+        assert!(srcset_urls(&image.srcset)[0].starts_with("/images/a-b-"));
+        assert!(processor
+            .cache_dir()
+            .join(&image.src["/images/".len()..])
+            .is_file());
+    }
+
+    #[test]
+    fn percent_encoded_urls_are_decoded() {
+        let (processor, blog) = fixture("encoded", &["my shot.png"]);
+
+        let image = processor.process("my%20shot.png", &blog).unwrap();
+        assert_eq!(image.width, 600);
+        assert!(processor
+            .process("/blog/my%20shot.png?v=2", &blog)
+            .is_some());
+        assert!(processor.process("my%2", &blog).is_none());
+    }
+
+    #[test]
+    fn gifs_keep_their_frames() {
+        let (processor, blog) = fixture("gif", &["anim.gif"]);
+
+        assert!(processor.process("anim.gif", &blog).is_none());
+    }
+}
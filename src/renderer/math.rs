@@ -0,0 +1,248 @@
+use latex2mathml::{latex_to_mathml, DisplayStyle};
+use log::debug;
+use tree_sitter::Node;
+
+use super::renderer::escape_html;
+
+// Private-use characters delimit math placeholders so tree-sitter sees plain text
+const PLACEHOLDER_START: char = '\u{E000}';
+const PLACEHOLDER_END: char = '\u{E001}';
+
+pub struct MathSpan {
+    tex: String,
+    display: bool,
+}
+
+/// Nodes whose source is used verbatim, so dollars in them are never math
+const VERBATIM_NODES: &[&str] = &[
+    "fenced_code_block",
+    "indented_code_block",
+    "code_span",
+    "link_destination",
+    "uri_autolink",
+    "www_autolink",
+    "email_autolink",
+    "html_block",
+    "html_comment",
+    "html_open_tag",
+    "html_close_tag",
+    "html_self_closing_tag",
+];
+
+/// Replaces `$…$` and `$$…$$` spans outside of code, link destinations and HTML with
+/// placeholders, so that their contents are not parsed as emphasis or links. `root`
+/// is the parsed tree of `source`.
+pub fn protect_math(source: &str, root: &Node) -> (String, Vec<MathSpan>) {
+    let mut verbatim = Vec::new();
+    collect_verbatim(root, &mut verbatim);
+
+    let mut output = String::with_capacity(source.len());
+    let mut spans = Vec::new();
+    let mut position = 0;
+    for (start, end) in verbatim {
+        if start < position {
+            continue;
+        }
+        output.push_str(&replace_math(&source[position..start], &mut spans));
+        output.push_str(&source[start..end]);
+        position = end;
+    }
+    output.push_str(&replace_math(&source[position..], &mut spans));
+
+    (output, spans)
+}
+
+fn collect_verbatim(node: &Node, ranges: &mut Vec<(usize, usize)>) {
+    if VERBATIM_NODES.contains(&node.kind()) {
+        ranges.push((node.start_byte(), node.end_byte()));
+        return;
+    }
+
+    for i in 0..node.child_count() {
+        if let Some(child) = node.child(i) {
+            collect_verbatim(&child, ranges);
+        }
+    }
+}
+
+/// Swaps the placeholders left by `protect_math` for rendered MathML.
+pub fn restore_math(text: &str, spans: &[MathSpan]) -> String {
+    replace_placeholders(text, spans, |span| render_math(&span.tex, span.display))
+}
+
+/// Swaps the placeholders left by `protect_math` for their TeX source, for places
+/// that can't hold markup such as attributes and anchors.
+pub fn restore_source(text: &str, spans: &[MathSpan]) -> String {
+    replace_placeholders(text, spans, |span| span.tex.trim().to_string())
+}
+
+/// Renders `text` as a display math block if it is nothing but `$$…$$`.
+pub fn display_block(text: &str, spans: &[MathSpan]) -> Option<String> {
+    let index = text
+        .trim()
+        .strip_prefix(PLACEHOLDER_START)?
+        .strip_suffix(PLACEHOLDER_END)?;
+    let span = spans.get(index.parse::<usize>().ok()?)?;
+    span.display.then(|| render_display_block(&span.tex))
+}
+
+/// Display math as a block of its own, for `$$…$$` paragraphs and `math` code blocks.
+pub fn render_display_block(tex: &str) -> String {
+    format!(
+        "<div class=\"math-display my-6 overflow-x-auto\">{}</div>\n",
+        render_math(tex, true)
+    )
+}
+
+fn replace_placeholders(
+    text: &str,
+    spans: &[MathSpan],
+    replace: impl Fn(&MathSpan) -> String,
+) -> String {
+    if spans.is_empty() {
+        return text.to_string();
+    }
+
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find(PLACEHOLDER_START) {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + PLACEHOLDER_START.len_utf8()..];
+
+        match after.find(PLACEHOLDER_END).and_then(|end| {
+            let span = spans.get(after[..end].parse::<usize>().ok()?)?;
+            Some((end, span))
+        }) {
+            Some((end, span)) => {
+                result.push_str(&replace(span));
+                rest = &after[end + PLACEHOLDER_END.len_utf8()..];
+            }
+            None => {
+                result.push(PLACEHOLDER_START);
+                rest = after;
+            }
+        }
+    }
+
+    result.push_str(rest);
+    result
+}
+
+/// Renders TeX to MathML, falling back to the escaped source with an error marker.
+pub fn render_math(tex: &str, display: bool) -> String {
+    let style = if display {
+        DisplayStyle::Block
+    } else {
+        DisplayStyle::Inline
+    };
+
+    // Unknown commands do not fail the conversion, they are inlined as error text
+    let result = match latex_to_mathml(tex.trim(), style) {
+        Ok(mathml) => match mathml.find("[PARSE ERROR: ") {
+            Some(pos) => Err(mathml[pos + 1..]
+                .split(']')
+                .next()
+                .unwrap_or_default()
+                .to_string()),
+            None => Ok(mathml),
+        },
+        Err(e) => Err(e.to_string()),
+    };
+
+    match result {
+        Ok(mathml) => mathml,
+        Err(e) => {
+            debug!("Unsupported math {:?}: {}", tex, e);
+            let tag = if display { "div" } else { "span" };
+            format!(
+                r#"<{tag} class="math-error font-mono text-gruvbox-red" title="{error}">{source} <span class="text-xs">[math error]</span></{tag}>"#,
+                tag = tag,
+                error = escape_html(&e),
+                source = escape_html(tex)
+            )
+        }
+    }
+}
+
+fn replace_math(text: &str, spans: &mut Vec<MathSpan>) -> String {
+    let mut output = String::with_capacity(text.len());
+    let mut i = 0;
+
+    while i < text.len() {
+        let rest = &text[i..];
+        let c = rest.chars().next().unwrap_or_default();
+
+        // Escaped dollars stay literal
+        if rest.starts_with("\\$") {
+            output.push_str("\\$");
+            i += 2;
+            continue;
+        }
+
+        if let Some(tex) = rest.strip_prefix("$$") {
+            if let Some(end) = tex.find("$$") {
+                output.push_str(&push_span(spans, &tex[..end], true));
+                i += end + 4;
+                continue;
+            }
+        } else if c == '$' {
+            if let Some(end) = find_inline_end(&rest[1..]) {
+                output.push_str(&push_span(spans, &rest[1..end + 1], false));
+                i += end + 2;
+                continue;
+            }
+        }
+
+        output.push(c);
+        i += c.len_utf8();
+    }
+
+    output
+}
+
+/// Finds the closing `$` of inline math: the content may not start or end with
+/// whitespace, span a blank line, or be followed by a digit (so `$5 and $10` stays text).
+fn find_inline_end(text: &str) -> Option<usize> {
+    if text.starts_with(char::is_whitespace) {
+        return None;
+    }
+
+    let mut escaped = false;
+    for (pos, c) in text.char_indices() {
+        match c {
+            '\\' => escaped = !escaped,
+            '$' if !escaped => {
+                let before = text[..pos].chars().last();
+                let after = text[pos + 1..].chars().next();
+                if pos == 0 || before.is_some_and(char::is_whitespace) {
+                    return None;
+                }
+                if after.is_some_and(|c| c.is_ascii_digit()) {
+                    return None;
+                }
+                return Some(pos);
+            }
+            _ => escaped = false,
+        }
+
+        if text[pos..].starts_with("\n\n") {
+            return None;
+        }
+    }
+
+    None
+}
+
+fn push_span(spans: &mut Vec<MathSpan>, tex: &str, display: bool) -> String {
+    spans.push(MathSpan {
+        tex: tex.to_string(),
+        display,
+    });
+    format!(
+        "{}{}{}",
+        PLACEHOLDER_START,
+        spans.len() - 1,
+        PLACEHOLDER_END
+    )
+}
@@ -1,4 +1,19 @@
+#[allow(clippy::module_inception)]
 mod renderer;
 pub use renderer::*;
 
+mod diagram;
 pub mod error;
+#[cfg(test)]
+pub mod fixtures;
+mod images;
+mod math;
+pub mod navigation;
+pub mod options;
+pub mod paths;
+pub mod posts;
+mod preview;
+pub mod search;
+pub mod series;
+pub mod store;
+pub use options::RenderOptions;
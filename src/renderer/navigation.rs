@@ -0,0 +1,59 @@
+use std::collections::HashMap;
+
+use super::{escape_html, posts::Post};
+
+/// Posts linked from a post's page: the listed posts published just before and
+/// after it, and the posts most related to it.
+pub struct Navigation {
+    /// Older neighbour
+    pub prev: Option<Post>,
+    /// Newer neighbour
+    pub next: Option<Post>,
+    pub related: Vec<Post>,
+}
+
+impl Navigation {
+    /// Template variables `{prev}`, `{next}` and `{related}`, as HTML. Each is empty
+    /// when there is nothing to link to.
+    pub fn variables(&self) -> HashMap<String, String> {
+        let link = |post: &Post, rel: &str, text: String| {
+            format!(
+                r#"<a href="{}" rel="{}" class="text-gruvbox-blue hover:text-gruvbox-aqua">{}</a>"#,
+                escape_html(&post.href()),
+                rel,
+                text
+            )
+        };
+
+        let prev = self.prev.as_ref().map_or_else(String::new, |post| {
+            link(post, "prev", format!("← {}", escape_html(post.title())))
+        });
+        let next = self.next.as_ref().map_or_else(String::new, |post| {
+            link(post, "next", format!("{} →", escape_html(post.title())))
+        });
+
+        let related = if self.related.is_empty() {
+            String::new()
+        } else {
+            let mut html = String::from(
+                "<section class=\"mt-8\">\n<h2 class=\"text-xl text-gruvbox-yellow font-normal mt-8 mb-6 relative\">Related posts</h2>\n<ul>\n",
+            );
+            for post in &self.related {
+                html.push_str(&format!(
+                    r#"<li><a href="{}" class="text-gruvbox-blue hover:text-gruvbox-aqua">{}</a></li>"#,
+                    escape_html(&post.href()),
+                    escape_html(post.title())
+                ));
+                html.push('\n');
+            }
+            html.push_str("</ul>\n</section>\n");
+            html
+        };
+
+        HashMap::from([
+            ("prev".to_string(), prev),
+            ("next".to_string(), next),
+            ("related".to_string(), related),
+        ])
+    }
+}
@@ -0,0 +1,133 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RenderOptions {
+    /// Callout kinds keyed by lowercase name, e.g. `note` for `> [!NOTE]`
+    pub callouts: HashMap<String, CalloutStyle>,
+    /// Wrap standalone images in numbered `<figure>`s captioned from their title
+    pub figures: bool,
+    pub images: ResponsiveImageOptions,
+    pub permalinks: PermalinkOptions,
+    pub preview: PreviewOptions,
+    /// Reading speed used to estimate reading times
+    pub words_per_minute: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PreviewOptions {
+    /// Serve drafts and scheduled posts as if they were published, e.g. for local writing
+    pub enabled: bool,
+    /// Key for signed `?preview=` links to single drafts. Links are disabled without one.
+    pub secret: Option<String>,
+    /// How long generated preview links stay valid
+    pub link_ttl_hours: u64,
+}
+
+impl Default for PreviewOptions {
+    fn default() -> Self {
+        PreviewOptions {
+            enabled: false,
+            secret: None,
+            link_ttl_hours: 72,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PermalinkOptions {
+    /// URL pattern for posts, e.g. `:year/:month/:slug`. The default `:path` keeps
+    /// URLs matching file paths.
+    pub pattern: String,
+    /// Use the slugified title as `:slug` when a post has no `slug` metadata
+    pub slugify_titles: bool,
+    /// File of `from to [status]` redirect rules, relative to the blog directory
+    pub redirects_file: PathBuf,
+}
+
+impl Default for PermalinkOptions {
+    fn default() -> Self {
+        PermalinkOptions {
+            pattern: ":path".to_string(),
+            slugify_titles: false,
+            redirects_file: PathBuf::from("_redirects"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ResponsiveImageOptions {
+    /// Generate resized variants and `srcset`s for images stored under the blog directory
+    pub enabled: bool,
+    pub widths: Vec<u32>,
+    pub sizes: String,
+    /// Directory where generated variants are cached
+    pub cache_dir: PathBuf,
+    /// URL prefix the cached variants are served from
+    pub url_prefix: String,
+}
+
+impl Default for ResponsiveImageOptions {
+    fn default() -> Self {
+        ResponsiveImageOptions {
+            enabled: true,
+            widths: vec![480, 960, 1440],
+            sizes: "(max-width: 768px) 100vw, 768px".to_string(),
+            cache_dir: PathBuf::from(".cache/images"),
+            url_prefix: "/images".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CalloutStyle {
+    pub title: String,
+    pub icon: String,
+    /// Gruvbox palette color used for the border, icon and title
+    pub color: String,
+}
+
+impl CalloutStyle {
+    fn new(title: &str, icon: &str, color: &str) -> Self {
+        CalloutStyle {
+            title: title.to_string(),
+            icon: icon.to_string(),
+            color: color.to_string(),
+        }
+    }
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        let callouts = HashMap::from([
+            ("note".to_string(), CalloutStyle::new("Note", "ℹ", "blue")),
+            ("tip".to_string(), CalloutStyle::new("Tip", "✦", "green")),
+            (
+                "important".to_string(),
+                CalloutStyle::new("Important", "❖", "purple"),
+            ),
+            (
+                "warning".to_string(),
+                CalloutStyle::new("Warning", "⚠", "yellow"),
+            ),
+            (
+                "caution".to_string(),
+                CalloutStyle::new("Caution", "✖", "red"),
+            ),
+        ]);
+
+        RenderOptions {
+            callouts,
+            figures: false,
+            images: ResponsiveImageOptions::default(),
+            permalinks: PermalinkOptions::default(),
+            preview: PreviewOptions::default(),
+            words_per_minute: 200,
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use std::{
+    io,
+    path::{Component, Path, PathBuf},
+};
+
+use log::error;
+
+use super::error::RendererError;
+
+/// Resolves a request path to a file inside `root`.
+///
+/// The path is validated before touching the filesystem: `..`, absolute and drive
+/// segments, backslashes and NUL bytes are rejected outright. The result is then
+/// canonicalized so symlinks pointing outside of `root` are rejected as well.
+pub fn resolve_within(root: &Path, relative: &str) -> Result<PathBuf, RendererError> {
+    if !is_safe_relative_path(relative) {
+        error!("Path traversal attempt detected: {:?}", relative);
+        return Err(RendererError::InvalidPathError(
+            "Path traversal not allowed".to_string(),
+        ));
+    }
+
+    let full_path = root.join(relative);
+    let not_found = |source: io::Error| RendererError::FileReadError {
+        path: full_path.to_string_lossy().to_string(),
+        source,
+    };
+
+    let canonical_root = root.canonicalize().map_err(not_found)?;
+    let canonical_path = full_path.canonicalize().map_err(not_found)?;
+
+    if !canonical_path.starts_with(&canonical_root) {
+        error!("Path escapes its root through a symlink: {:?}", full_path);
+        return Err(RendererError::InvalidPathError(
+            "Path traversal not allowed".to_string(),
+        ));
+    }
+
+    Ok(canonical_path)
+}
+
+fn is_safe_relative_path(relative: &str) -> bool {
+    if relative.contains(['\0', '\\']) {
+        return false;
+    }
+
+    Path::new(relative)
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+/// Resolves `url` against the directory URL of the current post, turning relative
+/// asset and post links into absolute URLs. Absolute and external URLs are unchanged.
+pub fn rewrite_relative_url(url: &str, base_url: &str) -> String {
+    if url.is_empty()
+        || url.starts_with(['#', '/', '?'])
+        || url.contains("://")
+        || url.starts_with("mailto:")
+        || url.starts_with("tel:")
+        || url.starts_with("data:")
+    {
+        return url.to_string();
+    }
+
+    let (path, suffix) = match url.find(['?', '#']) {
+        Some(pos) => url.split_at(pos),
+        None => (url, ""),
+    };
+
+    let mut segments: Vec<&str> = base_url.split('/').filter(|s| !s.is_empty()).collect();
+    // Never climb above the blog root, e.g. `/blog`
+    let root_len = 1.min(segments.len());
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                if segments.len() > root_len {
+                    segments.pop();
+                }
+            }
+            _ => segments.push(segment),
+        }
+    }
+
+    // Links to other posts point at their rendered page instead of the source
+    let mut resolved = format!("/{}", segments.join("/"));
+    if let Some(page) = resolved.strip_suffix(".md") {
+        resolved = page
+            .strip_suffix("index")
+            .filter(|dir| dir.ends_with('/'))
+            .unwrap_or(page)
+            .to_string();
+    }
+    if path.ends_with('/') && !resolved.ends_with('/') {
+        resolved.push('/');
+    }
+
+    format!("{}{}", resolved, suffix)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use super::*;
+
+    /// Creates `<tmp>/<name>/root/post.md` and `<tmp>/<name>/secret.md`, returning the root.
+    fn fixture(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("md_to_html_paths_{}_{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("root/nested")).unwrap();
+        fs::write(dir.join("root/post.md"), "# Post").unwrap();
+        fs::write(dir.join("root/nested/deep.md"), "# Deep").unwrap();
+        fs::write(dir.join("secret.md"), "# Secret").unwrap();
+        dir.join("root")
+    }
+
+    fn assert_rejected(root: &Path, path: &str) {
+        match resolve_within(root, path) {
+            Err(RendererError::InvalidPathError(_)) => {}
+            other => panic!("{:?} was not rejected: {:?}", path, other),
+        }
+    }
+
+    #[test]
+    fn resolves_files_inside_root() {
+        let root = fixture("inside");
+
+        let resolved = resolve_within(&root, "post.md").unwrap();
+        assert_eq!(resolved, root.canonicalize().unwrap().join("post.md"));
+        assert!(resolve_within(&root, "nested/deep.md").is_ok());
+        assert!(resolve_within(&root, "./nested/./deep.md").is_ok());
+    }
+
+    #[test]
+    fn rejects_parent_segments() {
+        let root = fixture("parent");
+
+        for path in [
+            "../secret.md",
+            "..",
+            "nested/../../secret.md",
+            "nested/../post.md",
+            "./../secret.md",
+            "nested/deep.md/../../../secret.md",
+        ] {
+            assert_rejected(&root, path);
+        }
+    }
+
+    #[test]
+    fn rejects_absolute_paths() {
+        let root = fixture("absolute");
+
+        assert_rejected(&root, "/etc/passwd");
+        assert_rejected(&root, root.join("post.md").to_str().unwrap());
+    }
+
+    #[test]
+    fn rejects_nul_bytes_and_backslashes() {
+        let root = fixture("nul");
+
+        assert_rejected(&root, "post.md\0.png");
+        assert_rejected(&root, "..\\secret.md");
+        assert_rejected(&root, "nested\\deep.md");
+        assert_rejected(&root, "C:\\Windows\\win.ini");
+    }
+
+    #[test]
+    fn traversal_is_rejected_whether_or_not_the_target_exists() {
+        let root = fixture("existence");
+
+        assert_rejected(&root, "../secret.md");
+        assert_rejected(&root, "../does-not-exist.md");
+    }
+
+    #[test]
+    fn missing_files_are_not_found() {
+        let root = fixture("missing");
+
+        match resolve_within(&root, "nope.md") {
+            Err(RendererError::FileReadError { source, .. }) => {
+                assert_eq!(source.kind(), io::ErrorKind::NotFound)
+            }
+            other => panic!("expected not found, got {:?}", other),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_symlinks_escaping_root() {
+        let root = fixture("symlink");
+        std::os::unix::fs::symlink(root.join("../secret.md"), root.join("link.md")).unwrap();
+        std::os::unix::fs::symlink(root.parent().unwrap(), root.join("up")).unwrap();
+
+        assert_rejected(&root, "link.md");
+        assert_rejected(&root, "up/secret.md");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn allows_symlinks_within_root() {
+        let root = fixture("symlink_inside");
+        std::os::unix::fs::symlink(root.join("nested/deep.md"), root.join("alias.md")).unwrap();
+
+        let resolved = resolve_within(&root, "alias.md").unwrap();
+        assert!(resolved.ends_with("nested/deep.md"));
+    }
+}
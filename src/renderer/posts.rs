@@ -0,0 +1,673 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use log::{error, info, warn};
+
+use super::{
+    error::RendererError,
+    options::PermalinkOptions,
+    paths::resolve_within,
+    renderer::{clean_request_path, extract_metadata, BLOG_URL_PREFIX},
+    series::Series,
+};
+
+/// A markdown file under `base_path` and the URL it is served at.
+#[derive(Debug, Clone)]
+pub struct Post {
+    pub path: PathBuf,
+    /// URL path relative to the blog prefix, without leading or trailing slashes
+    pub url: String,
+    pub metadata: HashMap<String, String>,
+}
+
+impl Post {
+    /// Whether this is a directory's `index.md`, i.e. a section page rather than a post
+    pub fn is_index(&self) -> bool {
+        self.path.file_stem().is_some_and(|stem| stem == "index")
+    }
+
+    /// Publication date from the `date` metadata
+    pub fn date(&self) -> Option<DateTime<Utc>> {
+        self.metadata.get("date").and_then(|date| parse_date(date))
+    }
+
+    /// Comma separated `tags` metadata, e.g. `tags: rust, web` or `tags: [rust, web]`
+    pub fn tags(&self) -> Vec<String> {
+        self.list("tags")
+    }
+
+    pub fn title(&self) -> &str {
+        self.metadata.get("title").map_or(&self.url, String::as_str)
+    }
+
+    /// Name of the series the post is a part of, from the `series` metadata
+    pub fn series(&self) -> Option<&str> {
+        self.metadata
+            .get("series")
+            .map(|series| series.trim())
+            .filter(|series| !series.is_empty())
+    }
+
+    /// Position in its series from `series_order`, parts without one come last
+    pub fn series_order(&self) -> Option<i64> {
+        self.metadata
+            .get("series_order")
+            .and_then(|order| order.trim().parse().ok())
+    }
+
+    pub fn is_draft(&self) -> bool {
+        self.flag("draft")
+    }
+
+    /// Unlisted posts are served at their URL but left out of listings
+    pub fn is_unlisted(&self) -> bool {
+        self.flag("unlisted")
+    }
+
+    /// Whether the post is publicly served: not a draft and not scheduled for later.
+    pub fn is_published(&self, now: DateTime<Utc>) -> bool {
+        !self.is_draft() && self.date().is_none_or(|date| date <= now)
+    }
+
+    /// Values of a comma separated metadata list, with optional brackets and quotes.
+    pub fn list(&self, key: &str) -> Vec<String> {
+        self.metadata
+            .get(key)
+            .map(|value| parse_list(value))
+            .unwrap_or_default()
+    }
+
+    fn flag(&self, key: &str) -> bool {
+        self.metadata
+            .get(key)
+            .is_some_and(|value| matches!(value.trim(), "true" | "yes"))
+    }
+
+    /// Absolute URL of the post, with a trailing slash for directory index pages
+    pub fn href(&self) -> String {
+        match (self.url.is_empty(), self.is_index()) {
+            (true, _) => format!("{}/", BLOG_URL_PREFIX),
+            (false, true) => format!("{}/{}/", BLOG_URL_PREFIX, self.url),
+            (false, false) => format!("{}/{}", BLOG_URL_PREFIX, self.url),
+        }
+    }
+}
+
+/// A permanent or temporary redirect from an old URL.
+#[derive(Debug, Clone)]
+pub struct Redirect {
+    /// Absolute URL or path to redirect to
+    pub to: String,
+    pub status: u16,
+}
+
+/// Two or more posts that resolve to the same URL.
+#[derive(Debug, Clone)]
+pub struct Collision {
+    pub url: String,
+    pub paths: Vec<PathBuf>,
+}
+
+/// Routing table from URLs to posts, built by scanning `base_path`.
+#[derive(Debug, Default)]
+pub struct PostIndex {
+    posts: Vec<Post>,
+    by_url: HashMap<String, usize>,
+    by_path: HashMap<PathBuf, usize>,
+    redirects: HashMap<String, Redirect>,
+    collisions: Vec<Collision>,
+    /// Series of the posts that can be listed, whether or not they are published yet
+    series: Vec<Series>,
+    /// Latest modification of a post, a scanned directory or the redirects file
+    modified: Option<SystemTime>,
+}
+
+impl PostIndex {
+    pub fn build(base_path: &Path, options: &PermalinkOptions) -> Result<Self, RendererError> {
+        let mut index = PostIndex::default();
+        let mut files = Vec::new();
+        collect_markdown_files(base_path, base_path, &mut files, &mut index.modified)?;
+        files.sort();
+
+        let mut claimed: HashMap<String, Vec<PathBuf>> = HashMap::new();
+
+        for relative in files {
+            if is_error_page(&relative) {
+                continue;
+            }
+
+            let path = match resolve_within(base_path, &relative) {
+                Ok(path) => path,
+                Err(e) => {
+                    warn!("Skipping {}: {}", relative, e);
+                    continue;
+                }
+            };
+
+            let content = fs::read_to_string(&path).map_err(|e| RendererError::FileReadError {
+                path: path.to_string_lossy().to_string(),
+                source: e,
+            })?;
+            update_modified(&mut index.modified, &path);
+            let metadata = extract_metadata(&content);
+            let url = permalink(&relative, &metadata, options);
+
+            claimed.entry(url.clone()).or_default().push(path.clone());
+            if index.by_url.contains_key(&url) {
+                continue;
+            }
+
+            index.by_url.insert(url.clone(), index.posts.len());
+            index.by_path.insert(path.clone(), index.posts.len());
+            index.posts.push(Post {
+                path,
+                url,
+                metadata,
+            });
+        }
+
+        for (url, paths) in claimed {
+            if paths.len() > 1 {
+                error!(
+                    "URL collision for /{}: {} (serving {})",
+                    url,
+                    paths
+                        .iter()
+                        .map(|p| p.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    paths[0].display()
+                );
+                index.collisions.push(Collision { url, paths });
+            }
+        }
+
+        let listable: Vec<Post> = index
+            .posts
+            .iter()
+            .filter(|post| !post.is_unlisted() && !post.is_index())
+            .cloned()
+            .collect();
+        index.series = Series::collect(&listable);
+
+        index.add_aliases();
+        let redirects_file = base_path.join(&options.redirects_file);
+        update_modified(&mut index.modified, &redirects_file);
+        index.add_redirects_file(&redirects_file)?;
+
+        info!(
+            "Indexed {} posts and {} redirects",
+            index.posts.len(),
+            index.redirects.len()
+        );
+        Ok(index)
+    }
+
+    pub fn get(&self, url: &str) -> Option<&Post> {
+        self.by_url.get(url).map(|i| &self.posts[*i])
+    }
+
+    pub fn redirect(&self, url: &str) -> Option<&Redirect> {
+        self.redirects.get(url)
+    }
+
+    pub fn redirects(&self) -> &HashMap<String, Redirect> {
+        &self.redirects
+    }
+
+    pub fn get_by_path(&self, path: &Path) -> Option<&Post> {
+        self.by_path.get(path).map(|i| &self.posts[*i])
+    }
+
+    pub fn posts(&self) -> &[Post] {
+        &self.posts
+    }
+
+    pub fn collisions(&self) -> &[Collision] {
+        &self.collisions
+    }
+
+    /// Series sorted by slug, parts include drafts and scheduled posts.
+    pub fn series(&self) -> &[Series] {
+        &self.series
+    }
+
+    /// When a post was last added, changed or removed, as far as the filesystem tells.
+    pub fn modified(&self) -> Option<SystemTime> {
+        self.modified
+    }
+
+    /// Redirects the comma separated `aliases` of each post to its URL.
+    fn add_aliases(&mut self) {
+        let aliases: Vec<(String, String)> = self
+            .posts
+            .iter()
+            .flat_map(|post| {
+                post.list("aliases")
+                    .into_iter()
+                    .filter(|alias| {
+                        let valid = is_valid_redirect_source(alias);
+                        if !valid {
+                            warn!(
+                                "{}: ignoring invalid alias {:?}",
+                                post.path.display(),
+                                alias
+                            );
+                        }
+                        valid
+                    })
+                    .map(|alias| (clean_request_path(&alias).to_string(), post.href()))
+            })
+            .collect();
+
+        for (alias, to) in aliases {
+            self.insert_redirect(&alias, Redirect { to, status: 301 });
+        }
+    }
+
+    /// Loads site-level redirects, one `from to [status]` rule per line. Targets
+    /// without a leading slash or scheme are relative to the blog prefix.
+    fn add_redirects_file(&mut self, path: &Path) -> Result<(), RendererError> {
+        if !path.is_file() {
+            return Ok(());
+        }
+
+        let content = fs::read_to_string(path).map_err(|e| RendererError::FileReadError {
+            path: path.to_string_lossy().to_string(),
+            source: e,
+        })?;
+
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let status = match fields.get(2).map(|status| status.parse::<u16>()) {
+                None => Ok(301),
+                Some(Ok(status @ (301 | 302 | 307 | 308))) => Ok(status),
+                Some(_) => Err(()),
+            };
+
+            match (fields.as_slice(), status) {
+                ([from, to, ..], Ok(status))
+                    if fields.len() <= 3 && is_valid_redirect_source(from) =>
+                {
+                    let to = if to.starts_with('/') || to.contains("://") {
+                        to.to_string()
+                    } else {
+                        format!("{}/{}", BLOG_URL_PREFIX, to)
+                    };
+                    self.insert_redirect(clean_request_path(from), Redirect { to, status });
+                }
+                _ => warn!(
+                    "{}:{}: invalid redirect rule {:?}",
+                    path.display(),
+                    number + 1,
+                    line
+                ),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Adds a redirect unless a post is served at that URL, which always wins.
+    fn insert_redirect(&mut self, from: &str, redirect: Redirect) {
+        if self.by_url.contains_key(from) {
+            warn!(
+                "Ignoring redirect from /{} to {}: a post is served there",
+                from, redirect.to
+            );
+            return;
+        }
+
+        if let Some(existing) = self.redirects.get(from) {
+            warn!(
+                "Duplicate redirect from /{}: keeping {}, ignoring {}",
+                from, existing.to, redirect.to
+            );
+            return;
+        }
+
+        self.redirects.insert(from.to_string(), redirect);
+    }
+}
+
+/// Computes the URL of a post from the permalink pattern.
+///
+/// Supported placeholders are `:path` (the file path), `:slug` (the `slug` metadata,
+/// the slugified title when enabled, or the file name) and `:year`, `:month`, `:day`
+/// from the `date` metadata. Index files are always served at their directory URL.
+fn permalink(
+    relative: &str,
+    metadata: &HashMap<String, String>,
+    options: &PermalinkOptions,
+) -> String {
+    let file_path = relative.trim_end_matches(".md");
+    let (dir, stem) = file_path.rsplit_once('/').unwrap_or(("", file_path));
+
+    if stem == "index" {
+        return dir.to_string();
+    }
+
+    let slug = metadata
+        .get("slug")
+        .map(|slug| slugify(slug))
+        .or_else(|| {
+            metadata
+                .get("title")
+                .filter(|_| options.slugify_titles)
+                .map(|title| slugify(title))
+        })
+        .filter(|slug| !slug.is_empty())
+        .unwrap_or_else(|| stem.to_string());
+
+    let path = if dir.is_empty() {
+        slug.clone()
+    } else {
+        format!("{}/{}", dir, slug)
+    };
+
+    let mut url = options
+        .pattern
+        .replace(":path", &path)
+        .replace(":slug", &slug);
+
+    if url.contains(":year") || url.contains(":month") || url.contains(":day") {
+        match metadata.get("date").and_then(|date| parse_date(date)) {
+            Some(date) => {
+                url = url
+                    .replace(":year", &date.format("%Y").to_string())
+                    .replace(":month", &date.format("%m").to_string())
+                    .replace(":day", &date.format("%d").to_string());
+            }
+            None => {
+                warn!(
+                    "{} has no valid date for permalink {}, serving it at /{}",
+                    relative, options.pattern, path
+                );
+                return path;
+            }
+        }
+    }
+
+    url.trim_matches('/').to_string()
+}
+
+/// Parses a `date` value: `2025-03-12`, `2025-03-12 09:30` or RFC 3339. Dates
+/// without an offset are UTC.
+pub fn parse_date(value: &str) -> Option<DateTime<Utc>> {
+    let value = value.trim();
+    if let Ok(date) = DateTime::parse_from_rfc3339(value) {
+        return Some(date.with_timezone(&Utc));
+    }
+
+    for format in [
+        "%Y-%m-%d %H:%M:%S",
+        "%Y-%m-%d %H:%M",
+        "%Y-%m-%dT%H:%M:%S",
+        "%Y-%m-%dT%H:%M",
+    ] {
+        if let Ok(date) = NaiveDateTime::parse_from_str(value, format) {
+            return Some(date.and_utc());
+        }
+    }
+
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|date| date.and_utc())
+}
+
+/// Items of a comma separated metadata value, with optional brackets and quotes.
+pub fn parse_list(value: &str) -> Vec<String> {
+    value
+        .trim()
+        .trim_matches(['[', ']'])
+        .split(',')
+        .map(|item| item.trim().trim_matches(['"', '\'']).to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+/// Whether a URL can be redirected from: relative to the blog, without `.` or `..`
+/// segments, so static export can write a stub for it inside the output directory.
+pub fn is_valid_redirect_source(from: &str) -> bool {
+    let from = clean_request_path(from);
+    !from.is_empty()
+        && from.split('/').all(|segment| {
+            !segment.is_empty()
+                && segment != "."
+                && segment != ".."
+                && !segment.contains(['\\', '\0'])
+        })
+}
+
+/// Error pages like `404.md` in the blog root are rendered for failed requests,
+/// not served as posts. Only 4xx and 5xx statuses have pages.
+fn is_error_page(relative: &str) -> bool {
+    relative
+        .strip_suffix(".md")
+        .filter(|stem| stem.len() == 3)
+        .and_then(|stem| stem.parse::<u16>().ok())
+        .is_some_and(|status| (400..600).contains(&status))
+}
+
+/// Lowercases text and joins its alphanumeric words with dashes, e.g.
+/// `"Hello, World!"` becomes `hello-world`.
+pub fn slugify(text: &str) -> String {
+    text.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Latest modification under `base_path` as `PostIndex::modified` would record it,
+/// found from file metadata alone so it is cheap enough to poll.
+pub fn latest_modification(base_path: &Path, options: &PermalinkOptions) -> Option<SystemTime> {
+    let mut modified = None;
+    scan_modified(base_path, &mut modified);
+    update_modified(&mut modified, &base_path.join(&options.redirects_file));
+    modified
+}
+
+/// Raises `modified` to the latest modification of `dir`, its subdirectories and
+/// the markdown files in them, skipping what `collect_markdown_files` skips.
+fn scan_modified(dir: &Path, modified: &mut Option<SystemTime>) {
+    update_modified(modified, dir);
+
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let is_hidden = entry.file_name().to_string_lossy().starts_with('.');
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        if is_hidden || (file_type.is_symlink() && path.is_dir()) {
+            continue;
+        }
+
+        if file_type.is_dir() {
+            scan_modified(&path, modified);
+        } else if path.extension().is_some_and(|ext| ext == "md") {
+            update_modified(modified, &path);
+        }
+    }
+}
+
+/// Raises `modified` to the modification time of `path`, if it exists.
+fn update_modified(modified: &mut Option<SystemTime>, path: &Path) {
+    let time = fs::metadata(path).and_then(|m| m.modified()).ok();
+    *modified = (*modified).max(time);
+}
+
+/// Collects the markdown files under `dir`. Directory modification times count
+/// towards `modified`, so removed and renamed posts are noticed too.
+fn collect_markdown_files(
+    base_path: &Path,
+    dir: &Path,
+    files: &mut Vec<String>,
+    modified: &mut Option<SystemTime>,
+) -> Result<(), RendererError> {
+    update_modified(modified, dir);
+
+    let entries = fs::read_dir(dir).map_err(|e| RendererError::FileReadError {
+        path: dir.to_string_lossy().to_string(),
+        source: e,
+    })?;
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let is_hidden = entry.file_name().to_string_lossy().starts_with('.');
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+
+        // Symlinked directories could loop, symlinked files are checked by `resolve_within`
+        if is_hidden || (file_type.is_symlink() && path.is_dir()) {
+            continue;
+        }
+
+        if file_type.is_dir() {
+            collect_markdown_files(base_path, &path, files, modified)?;
+        } else if path.extension().is_some_and(|ext| ext == "md") {
+            if let Ok(relative) = path.strip_prefix(base_path) {
+                files.push(relative.to_string_lossy().replace('\\', "/"));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    fn dated(date: &str) -> HashMap<String, String> {
+        HashMap::from([("date".to_string(), date.to_string())])
+    }
+
+    fn options(pattern: &str) -> PermalinkOptions {
+        PermalinkOptions {
+            pattern: pattern.to_string(),
+            ..PermalinkOptions::default()
+        }
+    }
+
+    #[test]
+    fn permalinks_use_the_parsed_date() {
+        let options = options(":year/:month/:day/:slug");
+
+        assert_eq!(
+            permalink("notes/post.md", &dated("2025-03-12"), &options),
+            "2025/03/12/post"
+        );
+        assert_eq!(
+            permalink("post.md", &dated("2025-03-12T09:30:00+02:00"), &options),
+            "2025/03/12/post"
+        );
+    }
+
+    #[test]
+    fn permalinks_with_invalid_dates_fall_back_to_the_path() {
+        let options = options(":year/:month/:day/:slug");
+
+        for date in ["2025-03-1é", "2025-é3-12", "2025-13-01", "2025-02-30", "é"] {
+            assert_eq!(
+                permalink("notes/post.md", &dated(date), &options),
+                "notes/post",
+                "{}",
+                date
+            );
+        }
+        assert_eq!(permalink("post.md", &HashMap::new(), &options), "post");
+    }
+
+    #[test]
+    fn aliases_and_redirect_rules_cannot_leave_the_blog() {
+        let dir = env::temp_dir().join(format!("md_to_html_posts_{}_aliases", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("post.md"),
+            "<!--\ntitle: Post\naliases: ../../x, a/./b, /old/post/, a//b\n-->\n# Post\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("_redirects"),
+            "../y /blog/post\nold/../../z post\nkept post 302\n",
+        )
+        .unwrap();
+
+        let index = PostIndex::build(&dir, &PermalinkOptions::default()).unwrap();
+        let mut sources: Vec<&String> = index.redirects().keys().collect();
+        sources.sort();
+        assert_eq!(sources, ["kept", "old/post"]);
+    }
+
+    #[test]
+    fn redirect_sources_are_paths_inside_the_blog() {
+        for valid in ["old", "/old/post/", "/blog/2020/post.html"] {
+            assert!(is_valid_redirect_source(valid), "{}", valid);
+        }
+        for invalid in [
+            "",
+            "/",
+            "..",
+            "../x",
+            "a/../../x",
+            "a/./b",
+            "a//b",
+            "a\\..\\b",
+        ] {
+            assert!(!is_valid_redirect_source(invalid), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn scheduled_posts_are_published_at_their_date() {
+        let post = |metadata: HashMap<String, String>| Post {
+            path: PathBuf::from("post.md"),
+            url: "post".to_string(),
+            metadata,
+        };
+        let date = |value: &str| parse_date(value).unwrap();
+
+        let scheduled = post(dated("2024-05-01T12:00:00Z"));
+        assert!(!scheduled.is_published(date("2024-05-01T11:59:59Z")));
+        assert!(scheduled.is_published(date("2024-05-01T12:00:00Z")));
+
+        let mut draft = dated("2024-05-01");
+        draft.insert("draft".to_string(), "true".to_string());
+        assert!(!post(draft).is_published(date("2030-01-01")));
+        assert!(post(HashMap::new()).is_published(date("2024-05-01")));
+    }
+
+    #[test]
+    fn only_error_status_pages_are_kept_out_of_the_index() {
+        let dir = env::temp_dir().join(format!("md_to_html_posts_{}_error_pages", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("notes")).unwrap();
+        for file in ["101.md", "404.md", "500.md", "2024.md", "notes/404.md"] {
+            fs::write(dir.join(file), "# Post\n").unwrap();
+        }
+
+        let index = PostIndex::build(&dir, &PermalinkOptions::default()).unwrap();
+        let mut urls: Vec<&str> = index.posts().iter().map(|post| post.url.as_str()).collect();
+        urls.sort();
+        assert_eq!(urls, ["101", "2024", "notes/404"]);
+    }
+}
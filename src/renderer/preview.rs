@@ -0,0 +1,131 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::apierror::ApiError;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Signs a preview token for the post at `url`, valid until `expires` (unix seconds).
+/// Tokens have the form `<expires>.<hex signature>`.
+pub fn sign(secret: &str, url: &str, expires: i64) -> String {
+    let signature = mac(secret, url, expires).finalize().into_bytes();
+    let hex: String = signature.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}.{}", expires, hex)
+}
+
+/// Checks a preview token for the post at `url`. Missing and expired tokens are
+/// `Unauthorized`, malformed or tampered ones (including tokens for another post)
+/// are `Forbidden`.
+pub fn verify(secret: &str, url: &str, token: &str, now: i64) -> Result<(), ApiError> {
+    if token.is_empty() {
+        return Err(ApiError::unauthorized("Preview token required"));
+    }
+
+    let (expires, signature) = token
+        .split_once('.')
+        .and_then(|(expires, signature)| {
+            Some((expires.parse::<i64>().ok()?, decode_hex(signature)?))
+        })
+        .ok_or_else(|| ApiError::forbidden("Malformed preview token"))?;
+
+    // Check the signature first, so an edited expiry is reported as tampering
+    mac(secret, url, expires)
+        .verify_slice(&signature)
+        .map_err(|_| ApiError::forbidden("Invalid preview token"))?;
+
+    if expires < now {
+        return Err(ApiError::unauthorized("Preview token expired"));
+    }
+
+    Ok(())
+}
+
+fn mac(secret: &str, url: &str, expires: i64) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(format!("{}\n{}", url, expires).as_bytes());
+    mac
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, ResponseError};
+
+    use super::*;
+
+    const NOW: i64 = 1_700_000_000;
+
+    fn status(token: &str, url: &str) -> Option<StatusCode> {
+        verify("secret", url, token, NOW)
+            .err()
+            .map(|error| error.status_code())
+    }
+
+    #[test]
+    fn signed_tokens_are_valid_until_they_expire() {
+        let token = sign("secret", "drafts/post", NOW + 60);
+
+        assert_eq!(status(&token, "drafts/post"), None);
+        assert!(verify("secret", "drafts/post", &token, NOW + 60).is_ok());
+        let expired = verify("secret", "drafts/post", &token, NOW + 61).unwrap_err();
+        assert_eq!(expired.status_code(), StatusCode::UNAUTHORIZED);
+        assert_eq!(status("", "drafts/post"), Some(StatusCode::UNAUTHORIZED));
+    }
+
+    #[test]
+    fn tampered_tokens_are_forbidden() {
+        let token = sign("secret", "drafts/post", NOW + 60);
+        let (expires, signature) = token.split_once('.').unwrap();
+
+        let later = format!("{}.{}", NOW + 3600, signature);
+        let flipped = match signature.strip_prefix('0') {
+            Some(rest) => format!("{}.1{}", expires, rest),
+            None => format!("{}.0{}", expires, &signature[1..]),
+        };
+        for tampered in [later, flipped] {
+            assert_eq!(
+                status(&tampered, "drafts/post"),
+                Some(StatusCode::FORBIDDEN)
+            );
+        }
+        assert_eq!(status(&token, "drafts/other"), Some(StatusCode::FORBIDDEN));
+        let other_secret = sign("other", "drafts/post", NOW + 60);
+        assert_eq!(
+            status(&other_secret, "drafts/post"),
+            Some(StatusCode::FORBIDDEN)
+        );
+    }
+
+    #[test]
+    fn malformed_tokens_are_forbidden() {
+        let signature = sign("secret", "post", NOW + 60);
+        let signature = signature.split_once('.').unwrap().1;
+
+        for token in [
+            "no-dot".to_string(),
+            format!("soon.{}", signature),
+            format!("{}.{}", NOW + 60, &signature[1..]),
+            format!("{}.zz{}", NOW + 60, &signature[2..]),
+            format!("{}.é{}", NOW + 60, &signature[2..]),
+            format!("{}.", NOW + 60),
+        ] {
+            assert_eq!(
+                status(&token, "post"),
+                Some(StatusCode::FORBIDDEN),
+                "{}",
+                token
+            );
+        }
+    }
+}
@@ -2,23 +2,130 @@ use std::{
     collections::HashMap,
     fs,
     path::{Path, PathBuf},
+    sync::{Mutex, RwLock},
+    time::{Duration, Instant, SystemTime},
 };
 
+use chrono::Utc;
 use log::{debug, error};
+use serde::Serialize;
 use tree_sitter::{Node, Parser};
 
 use crate::apierror::ApiError;
 
-use super::error::RendererError;
+use super::{
+    diagram::{DiagramCache, DIAGRAM_LANGUAGES},
+    error::RendererError,
+    images::{ImageProcessor, ResponsiveImage},
+    math,
+    navigation::Navigation,
+    options::{CalloutStyle, RenderOptions},
+    paths::{resolve_within, rewrite_relative_url},
+    posts::{latest_modification, slugify, Post, PostIndex, Redirect},
+    preview,
+    search::{SearchDocument, SearchHit, SearchIndex, SearchQuery},
+    series::Series,
+    store::{self, Precondition},
+};
+
+/// URL prefix the blog is served under
+pub const BLOG_URL_PREFIX: &str = "/blog";
+
+/// Error page used when `base_path` has no `<status>.md`
+const DEFAULT_ERROR_PAGE: &str = "<!--
+title: {status} {reason}
+-->
+
+# {status} {reason}
+
+{message}
+
+[Back to the blog](/blog/)
+";
+
+/// Marks the end of a post's excerpt
+const MORE_MARKER: &str = "<!-- more -->";
+
+/// Template variables set while rendering posts, removed from pages without them
+const POST_VARIABLES: &[&str] = &[
+    "prev",
+    "next",
+    "related",
+    "series",
+    "word_count",
+    "reading_time",
+    "excerpt",
+];
+
+/// Related posts linked from a post's page
+const RELATED_POSTS: usize = 3;
+
+/// Minimum time between post index rebuilds triggered by unknown URLs
+const POST_INDEX_REFRESH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How often the server checks for changed posts with `Renderer::refresh_changed_posts`,
+/// keeping the search index current without rebuilding it on queries
+pub const POST_WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// A heading in a post's table of contents.
+#[derive(Debug, Clone, Serialize)]
+pub struct TocEntry {
+    pub level: usize,
+    /// `id` of the rendered heading
+    pub id: String,
+    pub text: String,
+}
+
+/// A post's content rendered to an HTML fragment, without the page template.
+pub struct RenderedContent {
+    pub html: String,
+    pub metadata: HashMap<String, String>,
+    pub toc: Vec<TocEntry>,
+    /// Words outside code blocks and diagrams
+    pub word_count: usize,
+    /// Estimated minutes to read the post
+    pub reading_time: usize,
+    /// Plain text of the content before `<!-- more -->`, or of the first paragraph
+    pub excerpt: String,
+}
+
+/// What a blog URL resolves to.
+pub enum Route {
+    Post(Post),
+    Redirect(Redirect),
+}
+
+/// Which unpublished posts a request may see.
+#[derive(Clone, Copy)]
+pub enum Preview<'a> {
+    /// None
+    None,
+    /// The one a signed preview token was issued for
+    Token(&'a str),
+    /// All of them, for signed-in users
+    All,
+}
 
 pub struct Renderer {
     parser: Parser,
     base_path: PathBuf,
     template_path: PathBuf,
+    options: RenderOptions,
+    diagram_cache: DiagramCache,
+    image_processor: ImageProcessor,
+    posts: RwLock<PostIndex>,
+    posts_built_at: Mutex<Instant>,
+    search: RwLock<SearchIndex>,
+    // Serializes edits through the API
+    write_lock: Mutex<()>,
 }
 
 impl Renderer {
-    pub fn new<P: AsRef<Path>>(base_path: P, template_path: P) -> Result<Self, ApiError> {
+    pub fn new<P: AsRef<Path>>(
+        base_path: P,
+        template_path: P,
+        options: RenderOptions,
+    ) -> Result<Self, ApiError> {
         let mut parser = Parser::new();
         let markdown_language = tree_sitter_markdown::language();
 
@@ -29,12 +136,10 @@ impl Renderer {
         let base_path = base_path.as_ref().to_path_buf();
         let template_path = template_path.as_ref().to_path_buf();
 
-        if !base_path.exists() {
-            return Err(ApiError::not_found(format!(
-                "Blog directory not found: {}",
-                base_path.display()
-            )));
-        }
+        // Resolved paths are canonical, so the root they are checked against must be too
+        let base_path = base_path.canonicalize().map_err(|_| {
+            ApiError::not_found(format!("Blog directory not found: {}", base_path.display()))
+        })?;
 
         if !template_path.exists() {
             return Err(ApiError::not_found(format!(
@@ -43,22 +148,153 @@ impl Renderer {
             )));
         }
 
-        Ok(Renderer {
+        let image_processor = ImageProcessor::new(&base_path, options.images.clone());
+        let posts = PostIndex::build(&base_path, &options.permalinks)?;
+
+        let renderer = Renderer {
             parser,
             base_path,
             template_path,
-        })
+            options,
+            diagram_cache: DiagramCache::default(),
+            image_processor,
+            posts: RwLock::new(posts),
+            posts_built_at: Mutex::new(Instant::now()),
+            search: RwLock::new(SearchIndex::default()),
+            write_lock: Mutex::new(()),
+        };
+        renderer.update_search_index()?;
+        Ok(renderer)
     }
 
-    pub fn render(&self, path: &str) -> Result<String, ApiError> {
+    pub fn render(&self, post: &Post) -> Result<String, ApiError> {
         // Get markdown content
-        let md_path = self.resolve_markdown_path(path)?;
+        let md_path = &post.path;
         let markdown_content =
-            fs::read_to_string(&md_path).map_err(|e| RendererError::FileReadError {
+            fs::read_to_string(md_path).map_err(|e| RendererError::FileReadError {
                 path: md_path.to_string_lossy().to_string(),
                 source: e,
             })?;
 
+        let mut variables = self.navigation(post)?.variables();
+        let series_box = self
+            .series_of(post)?
+            .map_or_else(String::new, |series| series.box_html(post));
+        variables.insert("series".to_string(), series_box);
+
+        self.render_markdown(&markdown_content, md_path, variables)
+    }
+
+    /// Latest modification time of a post, the template it is rendered with and the
+    /// other posts, whose titles and text end up in its navigation.
+    pub fn last_modified(&self, post: &Post) -> Option<SystemTime> {
+        let posts_modified = self.posts.read().ok().and_then(|posts| posts.modified());
+        [&post.path, &self.template_path]
+            .iter()
+            .filter_map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+            .chain(posts_modified)
+            .max()
+    }
+
+    /// Renders an error page from `<status>.md` in `base_path` (e.g. `404.md`), or a
+    /// built-in page if there is none. `{status}`, `{reason}`, `{message}` and `{error}`
+    /// (the `error` code of JSON responses) in the page are replaced with the error details.
+    pub fn render_error_page(
+        &self,
+        status: u16,
+        reason: &str,
+        message: &str,
+        error_type: &str,
+    ) -> Result<String, ApiError> {
+        let page_path = self.base_path.join(format!("{}.md", status));
+        let markdown_content = if page_path.is_file() {
+            fs::read_to_string(&page_path).map_err(|e| RendererError::FileReadError {
+                path: page_path.to_string_lossy().to_string(),
+                source: e,
+            })?
+        } else {
+            DEFAULT_ERROR_PAGE.to_string()
+        };
+
+        let html = self.render_markdown(&markdown_content, &page_path, HashMap::new())?;
+        Ok(html
+            .replace("{status}", &status.to_string())
+            .replace("{reason}", &escape_html(reason))
+            .replace("{message}", &escape_html(message))
+            .replace("{error}", &escape_html(error_type)))
+    }
+
+    /// Renders a post's content without the page template.
+    pub fn render_fragment(&self, post: &Post) -> Result<RenderedContent, ApiError> {
+        let markdown_content =
+            fs::read_to_string(&post.path).map_err(|e| RendererError::FileReadError {
+                path: post.path.to_string_lossy().to_string(),
+                source: e,
+            })?;
+
+        self.render_content(&markdown_content, &post.path, None)
+    }
+
+    /// Renders markdown that is not stored in the blog, e.g. submitted for a preview.
+    /// Relative links and images resolve against the blog root. Rendering stops with
+    /// an error once `deadline` has passed.
+    pub fn render_source(
+        &self,
+        markdown_content: &str,
+        deadline: Option<Instant>,
+    ) -> Result<RenderedContent, ApiError> {
+        self.render_content(markdown_content, &self.base_path.join("index.md"), deadline)
+    }
+
+    /// Puts rendered content into the page template, like a post.
+    pub fn render_content_page(&self, content: RenderedContent) -> Result<String, ApiError> {
+        self.apply_page_template(content, HashMap::new())
+    }
+
+    /// Renders generated content, e.g. search results, with the page template.
+    pub fn render_page(&self, title: &str, content_html: String) -> Result<String, ApiError> {
+        let template =
+            fs::read_to_string(&self.template_path).map_err(|e| RendererError::FileReadError {
+                path: self.template_path.to_string_lossy().to_string(),
+                source: e,
+            })?;
+
+        let word_count = prose_word_count(&content_html);
+        let metadata = HashMap::from([
+            ("title".to_string(), escape_html(title)),
+            ("description".to_string(), String::new()),
+            ("author".to_string(), String::new()),
+            ("word_count".to_string(), word_count.to_string()),
+            (
+                "reading_time".to_string(),
+                self.reading_time(word_count).to_string(),
+            ),
+        ]);
+        Ok(apply_template(&template, content_html, metadata))
+    }
+
+    /// Estimated minutes to read `word_count` words.
+    fn reading_time(&self, word_count: usize) -> usize {
+        word_count.div_ceil(self.options.words_per_minute.max(1))
+    }
+
+    /// Renders a full page. `variables` fill template placeholders in addition to
+    /// the post's metadata.
+    fn render_markdown(
+        &self,
+        markdown_content: &str,
+        md_path: &Path,
+        variables: HashMap<String, String>,
+    ) -> Result<String, ApiError> {
+        let content = self.render_content(markdown_content, md_path, None)?;
+        self.apply_page_template(content, variables)
+    }
+
+    fn apply_page_template(
+        &self,
+        content: RenderedContent,
+        variables: HashMap<String, String>,
+    ) -> Result<String, ApiError> {
         // Get template content
         let template =
             fs::read_to_string(&self.template_path).map_err(|e| RendererError::FileReadError {
@@ -66,6 +302,20 @@ impl Renderer {
                 source: e,
             })?;
 
+        let mut metadata = content.metadata;
+        metadata.insert("word_count".to_string(), content.word_count.to_string());
+        metadata.insert("reading_time".to_string(), content.reading_time.to_string());
+        metadata.insert("excerpt".to_string(), escape_html(&content.excerpt));
+        metadata.extend(variables);
+        Ok(apply_template(&template, content.html, metadata))
+    }
+
+    fn render_content(
+        &self,
+        markdown_content: &str,
+        md_path: &Path,
+        deadline: Option<Instant>,
+    ) -> Result<RenderedContent, ApiError> {
         // Create a new parser for this request
         let mut parser = Parser::new();
         let markdown_language = tree_sitter_markdown::language();
@@ -73,64 +323,602 @@ impl Renderer {
             .set_language(markdown_language)
             .map_err(|e| RendererError::LanguageError(Box::new(e)))?;
 
-        // Parse markdown
-        let tree = parser.parse(&markdown_content, None).ok_or_else(|| {
-            RendererError::MarkdownParseError(Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Failed to parse markdown",
-            )))
-        })?;
+        let metadata = extract_metadata(markdown_content);
+        let markdown_content = strip_metadata(markdown_content);
+
+        // Parse markdown, then again with math protected from markdown parsing
+        let parse = |parser: &mut Parser, source: &str| {
+            parser.parse(source, None).ok_or_else(|| {
+                RendererError::MarkdownParseError(Box::new(std::io::Error::other(
+                    "Failed to parse markdown",
+                )))
+            })
+        };
+        let tree = parse(&mut parser, markdown_content)?;
+        let (markdown_content, math_spans) =
+            math::protect_math(markdown_content, &tree.root_node());
+        let tree = parse(&mut parser, &markdown_content)?;
 
         let root_node = tree.root_node();
 
-        // Extract metadata and references
-        let metadata = extract_metadata(&markdown_content);
+        // Extract references
         let mut link_references = HashMap::new();
         extract_link_references(&root_node, &markdown_content, &mut link_references);
+        let mut figure_labels = HashMap::new();
+        if self.options.figures {
+            extract_figure_labels(
+                &root_node,
+                &markdown_content,
+                &link_references,
+                &mut figure_labels,
+                &mut 0,
+            );
+        }
 
-        // Convert to HTML and apply template
-        let content_html = markdown_to_html(&root_node, &markdown_content, &link_references);
-        let final_html = apply_template(&template, content_html, metadata);
+        // Convert to HTML
+        let post_dir = md_path.parent().unwrap_or(&self.base_path);
+        let base_url = self.post_base_url(md_path);
+        let posts = self.posts.read().map_err(ApiError::internal_error)?;
+        let mut ctx = RenderContext {
+            link_references: &link_references,
+            options: &self.options,
+            diagram_cache: &self.diagram_cache,
+            image_processor: &self.image_processor,
+            post_dir,
+            base_url: &base_url,
+            posts: &posts,
+            figure_labels: &figure_labels,
+            math_spans: &math_spans,
+            deadline,
+            timed_out: false,
+            figure_count: 0,
+            attribute_list: (0, 0),
+            current_list_key: None,
+            is_first_heading: true,
+            is_first_paragraph: true,
+            heading_ids: HashMap::new(),
+            toc: Vec::new(),
+            first_paragraph: None,
+            excerpt: None,
+        };
+        let content_html = markdown_to_html(&root_node, &markdown_content, &mut ctx);
+        if ctx.timed_out {
+            return Err(ApiError::service_unavailable(
+                "Rendering did not finish in time",
+            ));
+        }
 
-        Ok(final_html)
+        let word_count = prose_word_count(&content_html);
+        let reading_time = self.reading_time(word_count);
+        let excerpt_html = ctx.excerpt.or(ctx.first_paragraph).unwrap_or_default();
+        let excerpt = html_to_text(&strip_code(&excerpt_html))
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        Ok(RenderedContent {
+            html: content_html,
+            metadata,
+            toc: ctx.toc,
+            word_count,
+            reading_time,
+            excerpt,
+        })
     }
 
-    fn resolve_markdown_path(&self, path: &str) -> Result<PathBuf, RendererError> {
-        let clean_path = path
-            .trim_start_matches('/')
-            .trim_start_matches("blog/")
-            .trim_end_matches('/');
+    /// Returns the path of a generated image variant in the image cache.
+    pub fn image_variant_path(&self, file_name: &str) -> Result<PathBuf, ApiError> {
+        if file_name.is_empty() || file_name.contains(['/', '\\']) || file_name.starts_with('.') {
+            return Err(RendererError::InvalidPathError(file_name.to_string()).into());
+        }
 
-        let mut full_path = self.base_path.clone();
+        Ok(self.image_processor.cache_dir().join(file_name))
+    }
 
-        if clean_path.is_empty() {
-            full_path.push("index.md");
-        } else {
-            full_path.push(format!("{}.md", clean_path));
+    /// Resolves a non-markdown file stored under `base_path`, e.g. an image next to a post.
+    pub fn asset_path(&self, path: &str) -> Result<PathBuf, ApiError> {
+        let clean_path = clean_request_path(path);
+        if clean_path
+            .split('/')
+            .any(|segment| segment.starts_with('.'))
+        {
+            return Err(RendererError::InvalidPathError(path.to_string()).into());
         }
 
-        debug!("Resolved markdown path: {:?}", full_path);
+        let full_path = resolve_within(&self.base_path, clean_path)?;
+        let is_markdown = full_path.extension().is_some_and(|ext| ext == "md");
 
-        if !full_path.exists() {
-            error!("File not found: {:?}", full_path);
+        if is_markdown || !full_path.is_file() {
+            error!("Asset not found: {:?}", full_path);
             return Err(RendererError::FileReadError {
                 path: full_path.to_string_lossy().to_string(),
                 source: std::io::Error::new(std::io::ErrorKind::NotFound, "File not found"),
-            });
+            }
+            .into());
         }
 
-        if !full_path.starts_with(&self.base_path) {
-            error!("Path traversal attempt detected");
-            return Err(RendererError::InvalidPathError(
-                "Path traversal not allowed".to_string(),
-            ));
+        Ok(full_path)
+    }
+
+    /// Looks up the post or redirect served at a request path. Unknown paths trigger
+    /// a (throttled) rebuild of the post index so new files are picked up.
+    ///
+    /// Drafts and scheduled posts are only served with a valid `preview_token`.
+    pub fn route(&self, path: &str, preview: Preview) -> Result<Route, ApiError> {
+        let clean_path = clean_request_path(path);
+
+        if let Some(route) = self.lookup_route(clean_path, preview)? {
+            return Ok(route);
         }
 
-        Ok(full_path)
+        if self.refresh_posts()? {
+            if let Some(route) = self.lookup_route(clean_path, preview)? {
+                return Ok(route);
+            }
+        }
+
+        error!("No post for path: {:?}", path);
+        Err(RendererError::FileReadError {
+            path: path.to_string(),
+            source: std::io::Error::new(std::io::ErrorKind::NotFound, "File not found"),
+        }
+        .into())
+    }
+
+    fn lookup_route(&self, url: &str, preview: Preview) -> Result<Option<Route>, ApiError> {
+        let posts = self.posts.read().map_err(ApiError::internal_error)?;
+        if let Some(post) = posts.get(url) {
+            debug!("Resolved {:?} to {:?}", url, post.path);
+            if !self.is_visible(post) {
+                match (preview, &self.options.preview.secret) {
+                    (Preview::All, _) => {}
+                    (Preview::Token(token), Some(secret)) => {
+                        preview::verify(secret, &post.url, token, Utc::now().timestamp())?
+                    }
+                    (Preview::Token(_), None) => {
+                        return Err(ApiError::forbidden("Previews are disabled"))
+                    }
+                    (Preview::None, _) => {
+                        debug!("{:?} is a draft or scheduled, hiding it", post.path);
+                        return Ok(None);
+                    }
+                }
+            }
+            return Ok(Some(Route::Post(post.clone())));
+        }
+
+        Ok(posts.redirect(url).cloned().map(Route::Redirect))
+    }
+
+    /// Signed preview URL for a post, given by URL or by markdown file path
+    /// relative to `base_path`.
+    pub fn preview_url(&self, target: &str) -> Result<String, ApiError> {
+        let secret = self.options.preview.secret.as_ref().ok_or_else(|| {
+            ApiError::bad_request("Set renderer.preview.secret to create preview links")
+        })?;
+
+        let posts = self.posts.read().map_err(ApiError::internal_error)?;
+        let post = if target.ends_with(".md") {
+            posts.get_by_path(&resolve_within(&self.base_path, target)?)
+        } else {
+            posts.get(clean_request_path(target))
+        }
+        .ok_or_else(|| ApiError::not_found(target))?;
+
+        let expires = i64::try_from(self.options.preview.link_ttl_hours)
+            .ok()
+            .and_then(chrono::Duration::try_hours)
+            .and_then(|ttl| Utc::now().checked_add_signed(ttl))
+            .ok_or_else(|| {
+                ApiError::internal_error("renderer.preview.link_ttl_hours is too large")
+            })?
+            .timestamp();
+        Ok(format!(
+            "{}?preview={}",
+            post.href(),
+            preview::sign(secret, &post.url, expires)
+        ))
     }
+
+    /// Whether a post may be served: published, or any post in preview mode.
+    fn is_visible(&self, post: &Post) -> bool {
+        self.options.preview.enabled || post.is_published(Utc::now())
+    }
+
+    /// Snapshot of the servable posts and the redirects.
+    pub fn posts(&self) -> Result<Vec<Post>, ApiError> {
+        let posts = self.posts.read().map_err(ApiError::internal_error)?;
+        Ok(posts
+            .posts()
+            .iter()
+            .filter(|post| self.is_visible(post))
+            .cloned()
+            .collect())
+    }
+
+    /// Posts for listings, newest first. Unlisted posts and section index pages are
+    /// never included, drafts and scheduled posts only in preview mode.
+    pub fn listed_posts(&self) -> Result<Vec<Post>, ApiError> {
+        let mut posts: Vec<Post> = self
+            .posts()?
+            .into_iter()
+            .filter(|post| !post.is_unlisted() && !post.is_index())
+            .collect();
+        posts.sort_by_key(|post| std::cmp::Reverse(post.date()));
+        Ok(posts)
+    }
+
+    pub fn redirects(&self) -> Result<HashMap<String, Redirect>, ApiError> {
+        let posts = self.posts.read().map_err(ApiError::internal_error)?;
+        Ok(posts.redirects().clone())
+    }
+
+    /// Listed posts matching a search, best first.
+    pub fn search(&self, query: &SearchQuery) -> Result<Vec<SearchHit>, ApiError> {
+        let index = self.search.read().map_err(ApiError::internal_error)?;
+        Ok(index.search(query))
+    }
+
+    /// The listed posts before and after `post` and the posts most related to it.
+    /// Posts that aren't listed have no neighbours.
+    pub fn navigation(&self, post: &Post) -> Result<Navigation, ApiError> {
+        // Newest first
+        let posts = self.listed_posts()?;
+        let (prev, next) = match posts.iter().position(|listed| listed.path == post.path) {
+            Some(i) => (
+                posts.get(i + 1).cloned(),
+                i.checked_sub(1).and_then(|i| posts.get(i)).cloned(),
+            ),
+            None => (None, None),
+        };
+
+        let index = self.search.read().map_err(ApiError::internal_error)?;
+        let related = index.related(&post.path);
+
+        Ok(Navigation {
+            prev,
+            next,
+            related,
+        })
+    }
+
+    /// Series formed by the listed posts.
+    pub fn all_series(&self) -> Result<Vec<Series>, ApiError> {
+        let posts = self.posts.read().map_err(ApiError::internal_error)?;
+        Ok(posts
+            .series()
+            .iter()
+            .filter_map(|series| self.visible_series(series))
+            .collect())
+    }
+
+    /// The series whose name has the slug `slug`.
+    pub fn series(&self, slug: &str) -> Result<Series, ApiError> {
+        let posts = self.posts.read().map_err(ApiError::internal_error)?;
+        posts
+            .series()
+            .iter()
+            .find(|series| series.slug == slug)
+            .and_then(|series| self.visible_series(series))
+            .ok_or_else(|| ApiError::not_found(format!("Series {}", slug)))
+    }
+
+    /// The series `post` is a part of, if it is listed.
+    pub fn series_of(&self, post: &Post) -> Result<Option<Series>, ApiError> {
+        let Some(name) = post.series() else {
+            return Ok(None);
+        };
+        let slug = slugify(name);
+        let posts = self.posts.read().map_err(ApiError::internal_error)?;
+        Ok(posts
+            .series()
+            .iter()
+            .find(|series| series.slug == slug)
+            .and_then(|series| self.visible_series(series))
+            .filter(|series| series.position(post).is_some()))
+    }
+
+    /// `series` with only the parts that may be served, `None` if that is none.
+    fn visible_series(&self, series: &Series) -> Option<Series> {
+        let parts: Vec<Post> = series
+            .parts
+            .iter()
+            .filter(|part| self.is_visible(part))
+            .cloned()
+            .collect();
+        (!parts.is_empty()).then(|| Series {
+            name: series.name.clone(),
+            slug: series.slug.clone(),
+            parts,
+        })
+    }
+
+    /// Searchable documents of the listed posts, newest first.
+    pub fn search_documents(&self) -> Result<Vec<SearchDocument>, ApiError> {
+        let index = self.search.read().map_err(ApiError::internal_error)?;
+        Ok(index.documents())
+    }
+
+    /// Brings the search index in line with the listed posts, re-rendering only the
+    /// posts whose files changed since they were indexed. Runs whenever the post index
+    /// is rebuilt, so reads never have to.
+    fn update_search_index(&self) -> Result<(), ApiError> {
+        let posts = self.listed_posts()?;
+
+        // Render outside the lock, so pages can read related posts meanwhile
+        let stale: Vec<(&Post, Option<SystemTime>)> = {
+            let index = self.search.read().map_err(ApiError::internal_error)?;
+            posts
+                .iter()
+                .map(|post| {
+                    (
+                        post,
+                        fs::metadata(&post.path).and_then(|m| m.modified()).ok(),
+                    )
+                })
+                .filter(|(post, modified)| index.is_stale(post, *modified))
+                .collect()
+        };
+        let mut documents = Vec::new();
+        for (post, modified) in stale {
+            debug!("Indexing {:?} for search", post.path);
+            match self.render_fragment(post) {
+                Ok(content) => {
+                    documents.push((post, SearchDocument::new(post, &content), modified))
+                }
+                Err(e) => error!("Failed to index {:?} for search: {}", post.path, e),
+            }
+        }
+
+        let mut index = self.search.write().map_err(ApiError::internal_error)?;
+        let mut changed = !documents.is_empty();
+        for (post, document, modified) in documents {
+            index.insert(post.clone(), document, modified);
+        }
+        changed |= index.retain(&posts.iter().map(|post| post.path.clone()).collect());
+        if changed {
+            index.update_related(RELATED_POSTS);
+        }
+
+        Ok(())
+    }
+
+    /// Rebuilds the post index unless it was rebuilt recently, returning whether it was.
+    pub fn refresh_posts(&self) -> Result<bool, ApiError> {
+        let mut built_at = self
+            .posts_built_at
+            .lock()
+            .map_err(ApiError::internal_error)?;
+        if built_at.elapsed() < POST_INDEX_REFRESH_INTERVAL {
+            return Ok(false);
+        }
+
+        self.rebuild_posts(&mut built_at)?;
+        Ok(true)
+    }
+
+    /// Rebuilds the post index if a post, a directory or the redirects file changed
+    /// since it was built, returning whether it was. Only reads file metadata when
+    /// nothing changed, see `POST_WATCH_INTERVAL`.
+    pub fn refresh_changed_posts(&self) -> Result<bool, ApiError> {
+        let is_current = || -> Result<bool, ApiError> {
+            let modified = self
+                .posts
+                .read()
+                .map_err(ApiError::internal_error)?
+                .modified();
+            Ok(modified == latest_modification(&self.base_path, &self.options.permalinks))
+        };
+        if is_current()? {
+            return Ok(false);
+        }
+
+        // Another refresh may have rebuilt it while this one waited for the lock
+        let mut built_at = self
+            .posts_built_at
+            .lock()
+            .map_err(ApiError::internal_error)?;
+        if is_current()? {
+            return Ok(false);
+        }
+        self.rebuild_posts(&mut built_at)?;
+        Ok(true)
+    }
+
+    /// Rebuilds the post index right away, e.g. after a post was edited.
+    pub fn reload_posts(&self) -> Result<(), ApiError> {
+        let mut built_at = self
+            .posts_built_at
+            .lock()
+            .map_err(ApiError::internal_error)?;
+        self.rebuild_posts(&mut built_at)
+    }
+
+    fn rebuild_posts(&self, built_at: &mut Instant) -> Result<(), ApiError> {
+        let index = PostIndex::build(&self.base_path, &self.options.permalinks)?;
+        *self.posts.write().map_err(ApiError::internal_error)? = index;
+        *built_at = Instant::now();
+        self.update_search_index()
+    }
+
+    /// A post's markdown source and its ETag, whether or not the post is published.
+    pub fn post_source(&self, url: &str) -> Result<(Post, String, String), ApiError> {
+        let post = self
+            .find_post(url)?
+            .ok_or_else(|| ApiError::not_found(url))?;
+        let source = fs::read_to_string(&post.path).map_err(|e| RendererError::FileReadError {
+            path: post.path.to_string_lossy().to_string(),
+            source: e,
+        })?;
+        let etag = store::source_etag(&source);
+        Ok((post, source, etag))
+    }
+
+    /// Creates or replaces the post at `url` with `source`. New posts are stored as
+    /// `<url>.md` in `base_path`. Returns the saved post, its ETag and whether it is new.
+    pub fn save_post(
+        &self,
+        url: &str,
+        source: &str,
+        precondition: &Precondition,
+    ) -> Result<(Post, String, bool), ApiError> {
+        self.edit_post(url, precondition, |_| Ok(source.to_string()))
+    }
+
+    /// Changes metadata of an existing post (`None` values remove a key) and optionally
+    /// replaces its markdown body.
+    pub fn update_post(
+        &self,
+        url: &str,
+        metadata: &[(String, Option<String>)],
+        body: Option<&str>,
+        precondition: &Precondition,
+    ) -> Result<(Post, String), ApiError> {
+        let (post, etag, _) = self.edit_post(url, precondition, |current| {
+            let current = current.ok_or_else(|| ApiError::not_found(url))?;
+            let (mut fields, current_body) = store::split_metadata(current);
+
+            for (key, value) in metadata {
+                let key = key.to_lowercase();
+                let position = fields.iter().position(|(existing, _)| *existing == key);
+                match (position, value) {
+                    (Some(i), Some(value)) => fields[i].1 = value.trim().to_string(),
+                    (None, Some(value)) => fields.push((key, value.trim().to_string())),
+                    (Some(i), None) => {
+                        fields.remove(i);
+                    }
+                    (None, None) => {}
+                }
+            }
+
+            Ok(store::join_metadata(&fields, body.unwrap_or(current_body)))
+        })?;
+
+        Ok((post, etag))
+    }
+
+    pub fn delete_post(&self, url: &str, precondition: &Precondition) -> Result<(), ApiError> {
+        let _guard = self.write_lock.lock().map_err(ApiError::internal_error)?;
+        let (post, _, etag) = self.post_source(url)?;
+        if !precondition.holds(Some(&etag)) {
+            return Err(ApiError::precondition_failed(format!(
+                "/{} has changed",
+                clean_request_path(url)
+            )));
+        }
+
+        fs::remove_file(&post.path).map_err(|e| RendererError::FileWriteError {
+            path: post.path.to_string_lossy().to_string(),
+            source: e,
+        })?;
+        self.reload_posts()
+    }
+
+    /// Writes the source built from the current one (if the post exists), after
+    /// checking the precondition and validating the metadata. Edits are serialized so
+    /// the precondition still holds when the file is written.
+    fn edit_post(
+        &self,
+        url: &str,
+        precondition: &Precondition,
+        build: impl FnOnce(Option<&str>) -> Result<String, ApiError>,
+    ) -> Result<(Post, String, bool), ApiError> {
+        let _guard = self.write_lock.lock().map_err(ApiError::internal_error)?;
+        let url = clean_request_path(url);
+
+        let current = match self.find_post(url)? {
+            Some(_) => Some(self.post_source(url)?),
+            None => None,
+        };
+        let current_etag = current.as_ref().map(|(_, _, etag)| etag.as_str());
+        if !precondition.holds(current_etag) {
+            return Err(ApiError::precondition_failed(match current_etag {
+                Some(_) => format!("/{} has changed", url),
+                None => format!("/{} does not exist", url),
+            }));
+        }
+
+        let source = build(current.as_ref().map(|(_, source, _)| source.as_str()))?;
+        let (metadata, _) = store::split_metadata(&source);
+        store::validate_metadata(&metadata)?;
+
+        let (path, created) = match current {
+            Some((post, _, _)) => (post.path, false),
+            None => {
+                store::validate_slug(url)?;
+                let path = self.base_path.join(format!("{}.md", url));
+                if path.exists() {
+                    return Err(ApiError::validation_error(format!(
+                        "/{} is taken by a file that is not served at that URL",
+                        url
+                    )));
+                }
+                (path, true)
+            }
+        };
+
+        store::write_atomic(&path, &source)?;
+        self.reload_posts()?;
+
+        let posts = self.posts.read().map_err(ApiError::internal_error)?;
+        let post = posts
+            .get_by_path(&path.canonicalize().unwrap_or(path))
+            .cloned()
+            .ok_or_else(|| ApiError::internal_error(format!("/{} was not indexed", url)))?;
+        Ok((post, store::source_etag(&source), created))
+    }
+
+    /// The post at `url` regardless of whether it is published, rebuilding the
+    /// index (throttled) if it is unknown.
+    fn find_post(&self, url: &str) -> Result<Option<Post>, ApiError> {
+        let url = clean_request_path(url);
+        let lookup = || -> Result<Option<Post>, ApiError> {
+            let posts = self.posts.read().map_err(ApiError::internal_error)?;
+            Ok(posts.get(url).cloned())
+        };
+
+        match lookup()? {
+            Some(post) => Ok(Some(post)),
+            None if self.refresh_posts()? => lookup(),
+            None => Ok(None),
+        }
+    }
+
+    /// URL of the directory containing a post, used to resolve its relative links.
+    fn post_base_url(&self, md_path: &Path) -> String {
+        let relative_dir = md_path
+            .strip_prefix(&self.base_path)
+            .ok()
+            .and_then(Path::parent)
+            .map(|dir| dir.to_string_lossy().replace('\\', "/"))
+            .unwrap_or_default();
+
+        if relative_dir.is_empty() {
+            format!("{}/", BLOG_URL_PREFIX)
+        } else {
+            format!("{}/{}/", BLOG_URL_PREFIX, relative_dir)
+        }
+    }
+}
+
+/// Whether a blog request targets a file other than a post, e.g. `diagram.png`.
+pub fn is_asset_request(path: &str) -> bool {
+    let file_name = clean_request_path(path)
+        .rsplit('/')
+        .next()
+        .unwrap_or_default();
+    file_name
+        .rsplit_once('.')
+        .is_some_and(|(stem, ext)| !stem.is_empty() && ext != "md")
 }
 
-fn extract_metadata(markdown: &str) -> HashMap<String, String> {
+pub(super) fn clean_request_path(path: &str) -> &str {
+    path.trim_start_matches('/')
+        .trim_start_matches("blog/")
+        .trim_end_matches('/')
+}
+
+pub(super) fn extract_metadata(markdown: &str) -> HashMap<String, String> {
     let mut metadata = HashMap::new();
 
     let lines: Vec<&str> = markdown.lines().collect();
@@ -167,6 +955,25 @@ fn extract_metadata(markdown: &str) -> HashMap<String, String> {
     metadata
 }
 
+/// The markdown after the leading metadata comments read by `extract_metadata`,
+/// which are not rendered. A `<!-- more -->` among them is kept, it ends the excerpt.
+fn strip_metadata(markdown: &str) -> &str {
+    let mut rest = markdown;
+    loop {
+        let trimmed = rest.trim_start();
+        if trimmed.starts_with(MORE_MARKER) {
+            return rest;
+        }
+        let Some(comment) = trimmed.strip_prefix("<!--") else {
+            return rest;
+        };
+        let Some(end) = comment.find("-->") else {
+            return rest;
+        };
+        rest = &comment[end + 3..];
+    }
+}
+
 fn parse_metadata_line(line: &str, metadata: &mut HashMap<String, String>) {
     let parts: Vec<&str> = line.splitn(2, ':').collect();
     if parts.len() == 2 {
@@ -241,64 +1048,416 @@ fn apply_template(
         let placeholder = format!("{{{}}}", key);
         result = result.replace(&placeholder, &value);
     }
+    for key in POST_VARIABLES {
+        result = result.replace(&format!("{{{}}}", key), "");
+    }
 
     result = result.replace("{content}", &content_html);
 
-    result
-}
+    result
+}
+
+struct Image {
+    url: String,
+    alt: String,
+    title: String,
+    width: Option<String>,
+    height: Option<String>,
+    classes: String,
+    style: String,
+    /// HTML once the image is rendered, see `render_image`
+    caption: Option<String>,
+    label: Option<String>,
+    responsive: Option<ResponsiveImage>,
+}
+
+impl Image {
+    fn to_html(&self) -> String {
+        let src = self.responsive.as_ref().map_or(&self.url, |r| &r.src);
+        let mut img_tag = format!(
+            r#"<img src="{}" alt="{}" title="{}" class="{}""#,
+            escape_html(src),
+            escape_html(&self.alt),
+            escape_html(&self.title),
+            escape_html(&self.classes)
+        );
+
+        // Intrinsic dimensions reserve layout space, unless overridden by attributes
+        let intrinsic = self
+            .responsive
+            .as_ref()
+            .filter(|_| self.width.is_none() && self.height.is_none())
+            .map(|r| (r.width.to_string(), r.height.to_string()));
+        let (width, height) = match &intrinsic {
+            Some((w, h)) => (Some(w), Some(h)),
+            None => (self.width.as_ref(), self.height.as_ref()),
+        };
+
+        if let Some(w) = width {
+            img_tag.push_str(&format!(r#" width="{}""#, escape_html(w)));
+        }
+        if let Some(h) = height {
+            img_tag.push_str(&format!(r#" height="{}""#, escape_html(h)));
+        }
+        if !self.style.is_empty() {
+            img_tag.push_str(&format!(r#" style="{}""#, escape_html(&self.style)));
+        }
+
+        let Some(responsive) = &self.responsive else {
+            img_tag.push('>');
+            return img_tag;
+        };
+
+        img_tag.push_str(&format!(
+            r#" srcset="{}" sizes="{}" loading="lazy" decoding="async">"#,
+            escape_html(&responsive.srcset),
+            escape_html(&responsive.sizes)
+        ));
+
+        format!(
+            r#"<picture><source type="image/webp" srcset="{}" sizes="{}">{}</picture>"#,
+            escape_html(&responsive.webp_srcset),
+            escape_html(&responsive.sizes),
+            img_tag
+        )
+    }
+
+    fn to_figure_html(&self, number: usize) -> String {
+        let id = self
+            .label
+            .as_ref()
+            .map(|label| format!(r#" id="fig-{}""#, escape_html(label)))
+            .unwrap_or_default();
+        let caption = match &self.caption {
+            Some(caption) => caption.clone(),
+            None => escape_html(&self.title),
+        };
+        let caption = if caption.is_empty() {
+            String::new()
+        } else {
+            format!(" {}", caption)
+        };
+
+        format!(
+            r#"<figure{} class="my-6 flex flex-col items-center">
+{}
+<figcaption class="text-sm text-gruvbox-fg-dim mt-2"><span class="text-gruvbox-orange">Figure {}.</span>{}</figcaption>
+</figure>
+"#,
+            id,
+            self.to_html(),
+            number,
+            caption
+        )
+    }
+}
+
+/// Returns the image of a paragraph that contains nothing else.
+fn standalone_image<'t>(node: &Node<'t>, source: &str) -> Option<Node<'t>> {
+    let mut image = None;
+    let mut attribute_list_end = 0;
+
+    for i in 0..node.child_count() {
+        let child = node.child(i)?;
+        if child.kind() == "image" && image.is_none() {
+            image = Some(child);
+            attribute_list_end = image_attribute_list(&child, source).map_or(0, |(_, end)| end);
+            continue;
+        }
+
+        // Anything but the image's own attribute list and whitespace makes it inline
+        let start = child.start_byte().max(attribute_list_end);
+        if start < child.end_byte() && !source[start..child.end_byte()].trim().is_empty() {
+            return None;
+        }
+    }
+
+    image
+}
+
+/// Numbers standalone images in document order, mapping figure labels to numbers
+/// so cross references can point at figures defined further down.
+fn extract_figure_labels(
+    node: &Node,
+    source: &str,
+    link_references: &HashMap<String, (String, String)>,
+    labels: &mut HashMap<String, usize>,
+    count: &mut usize,
+) {
+    match node.kind() {
+        // List paragraphs are rendered inline, never as figures
+        "tight_list" | "loose_list" => {}
+        "paragraph" => {
+            if let Some(image) = standalone_image(node, source) {
+                *count += 1;
+                if let Some(label) = parse_image(&image, source, link_references).label {
+                    labels.insert(label, *count);
+                }
+            }
+        }
+        _ => {
+            for i in 0..node.child_count() {
+                if let Some(child) = node.child(i) {
+                    extract_figure_labels(&child, source, link_references, labels, count);
+                }
+            }
+        }
+    }
+}
+
+/// Replaces `{fig:label}` references with links to the numbered figure.
+fn resolve_figure_refs(text: &str, labels: &HashMap<String, usize>) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find("{fig:") {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 5..];
+
+        match after
+            .find('}')
+            .and_then(|end| Some((end, labels.get(after[..end].trim())?)))
+        {
+            Some((end, number)) => {
+                result.push_str(&format!(
+                    r##"<a href="#fig-{}" class="text-gruvbox-blue hover:text-gruvbox-aqua">Figure {}</a>"##,
+                    escape_html(after[..end].trim()),
+                    number
+                ));
+                rest = &after[end + 1..];
+            }
+            None => {
+                debug!("Unresolved figure reference in: {}", text);
+                result.push_str("{fig:");
+                rest = after;
+            }
+        }
+    }
+
+    result.push_str(rest);
+    result
+}
+
+fn parse_image(
+    node: &Node,
+    source: &str,
+    link_references: &HashMap<String, (String, String)>,
+) -> Image {
+    let mut image = Image {
+        url: String::new(),
+        alt: String::new(),
+        title: String::new(),
+        width: None,
+        height: None,
+        classes: String::from("max-w-full h-auto my-4 rounded-lg shadow-lg"),
+        style: String::new(),
+        caption: None,
+        label: None,
+        responsive: None,
+    };
+    let mut reference_label = None;
+
+    for i in 0..node.child_count() {
+        if let Some(child) = node.child(i) {
+            if let Ok(text) = child.utf8_text(source.as_bytes()) {
+                match child.kind() {
+                    "image_description" => image.alt = text.trim().to_string(),
+                    "link_destination" => {
+                        image.url = text
+                            .trim_start_matches('<')
+                            .trim_end_matches('>')
+                            .to_string()
+                    }
+                    "link_title" => {
+                        image.title = text
+                            .trim_matches(|c| c == '"' || c == '\'' || c == '(' || c == ')')
+                            .replace("\\\"", "\"")
+                    }
+                    "link_label" => reference_label = Some(text),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    // Reference-style images: ![alt][label] or shortcut ![label]
+    if image.url.is_empty() {
+        let key = reference_label.unwrap_or(&image.alt).to_lowercase();
+        if let Some((destination, title)) = link_references.get(&key) {
+            image.url = destination.clone();
+            if image.title.is_empty() {
+                image.title = title.clone();
+            }
+        }
+    }
+
+    if let Some((start, end)) = image_attribute_list(node, source) {
+        for attr in split_attributes(&source[start + 1..end - 1]) {
+            if let Some(class) = attr.strip_prefix('.') {
+                image.classes.push_str(&format!(" {}", class));
+                continue;
+            }
+            if let Some(label) = attr.strip_prefix('#') {
+                image.label = Some(label.to_string());
+                continue;
+            }
+
+            let Some((key, value)) = attr.split_once('=') else {
+                debug!("Unknown image attribute: {}", attr);
+                continue;
+            };
+            let value = value.trim().trim_matches('"').to_string();
+
+            match key.trim() {
+                "width" => image.width = Some(value),
+                "height" => image.height = Some(value),
+                "class" => image.classes.push_str(&format!(" {}", value)),
+                "style" => image.style = value,
+                "caption" => image.caption = Some(value),
+                "label" | "id" => image.label = Some(value),
+                "preset" => {
+                    image.classes = match value.as_str() {
+                        "avatar" => "w-32 h-32 rounded-full object-cover".to_string(),
+                        "banner" => "w-full h-64 object-cover".to_string(),
+                        "thumbnail" => "w-48 h-48 object-cover rounded".to_string(),
+                        _ => image.classes,
+                    }
+                }
+                key => debug!("Unknown image attribute: {}={}", key, value),
+            }
+        }
+    }
+
+    image
+}
+
+/// Parses an image and attaches its responsive variants when it is stored locally.
+fn render_image(node: &Node, source: &str, ctx: &RenderContext) -> Image {
+    let mut image = parse_image(node, source, ctx.link_references);
+    image.alt = math::restore_source(&image.alt, ctx.math_spans);
+    image.title = math::restore_source(&image.title, ctx.math_spans);
+    image.caption = image
+        .caption
+        .map(|caption| math::restore_math(&escape_html(&caption), ctx.math_spans));
+    image.responsive = ctx.image_processor.process(&image.url, ctx.post_dir);
+    image.url = rewrite_relative_url(&image.url, ctx.base_url);
+    image
+}
+
+/// Finds a `{width=300 .class preset=avatar}` attribute list written directly after
+/// an image, returning its byte range including the braces.
+fn image_attribute_list(node: &Node, source: &str) -> Option<(usize, usize)> {
+    let start = node.end_byte();
+    if !source[start..].starts_with('{') {
+        return None;
+    }
+
+    let mut in_quotes = false;
+    for (pos, c) in source[start..].char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            '}' if !in_quotes => return Some((start, start + pos + 1)),
+            '\n' => return None,
+            _ => {}
+        }
+    }
+
+    None
+}
+
+/// Splits an attribute list on whitespace, keeping quoted values together.
+fn split_attributes(attrs: &str) -> Vec<String> {
+    let mut attributes = Vec::new();
+    let mut current_attr = String::new();
+    let mut in_quotes = false;
+
+    for c in attrs.chars() {
+        match c {
+            '"' => {
+                current_attr.push(c);
+                in_quotes = !in_quotes;
+            }
+            c if c.is_whitespace() && !in_quotes => {
+                if !current_attr.is_empty() {
+                    attributes.push(current_attr.clone());
+                    current_attr.clear();
+                }
+            }
+            _ => current_attr.push(c),
+        }
+    }
+    if !current_attr.is_empty() {
+        attributes.push(current_attr);
+    }
+
+    attributes
+}
+
+/// State shared across the recursive walk of a single document.
+struct RenderContext<'a> {
+    link_references: &'a HashMap<String, (String, String)>,
+    options: &'a RenderOptions,
+    diagram_cache: &'a DiagramCache,
+    image_processor: &'a ImageProcessor,
+    /// Directory of the post being rendered, relative image paths resolve against it
+    post_dir: &'a Path,
+    /// URL of the post's directory, relative links are rewritten against it
+    base_url: &'a str,
+    posts: &'a PostIndex,
+    figure_labels: &'a HashMap<String, usize>,
+    /// Math replaced by placeholders before parsing, restored in text
+    math_spans: &'a [math::MathSpan],
+    /// Rendering stops once this has passed, setting `timed_out`
+    deadline: Option<Instant>,
+    timed_out: bool,
+    figure_count: usize,
+    /// Byte range of the last image attribute list, which is not rendered as text
+    attribute_list: (usize, usize),
+    current_list_key: Option<String>,
+    is_first_heading: bool,
+    is_first_paragraph: bool,
+    heading_ids: HashMap<String, usize>,
+    toc: Vec<TocEntry>,
+    /// Inner HTML of the first paragraph
+    first_paragraph: Option<String>,
+    /// HTML before the `<!-- more -->` marker, without the title
+    excerpt: Option<String>,
+}
+
+fn convert_node_to_html(node: &Node, source: &str, html: &mut String, ctx: &mut RenderContext) {
+    if ctx.timed_out
+        || ctx
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    {
+        ctx.timed_out = true;
+        return;
+    }
+
+    let (attribute_list_start, attribute_list_end) = ctx.attribute_list;
+    if node.start_byte() >= attribute_list_start && node.end_byte() <= attribute_list_end {
+        return;
+    }
 
-fn convert_node_to_html(
-    node: &Node,
-    source: &str,
-    html: &mut String,
-    link_references: &HashMap<String, (String, String)>,
-    current_list_key: &mut Option<String>,
-    is_first_heading: &mut bool,
-    is_first_paragraph: &mut bool,
-) {
     match node.kind() {
         "document" => {
-            let mut i = 0;
-            while i < node.child_count() {
+            let mut body_start = html.len();
+            for i in 0..node.child_count() {
                 if let Some(child) = node.child(i) {
-                    match child.kind() {
-                        "list" | "list_item" | "list_marker" => {
-                            i += 1;
-                            continue;
-                        }
-                        _ => {
-                            if let Ok(text) = child.utf8_text(source.as_bytes()) {
-                                let trimmed = text.trim();
-
-                                if trimmed.starts_with("- ") || trimmed.starts_with("* ") {
-                                    html.push_str("<ul class=\"space-y-2\">\n");
-                                    process_list_items(text, html, false);
-                                    html.push_str("</ul>\n");
-                                } else if trimmed
-                                    .chars()
-                                    .next()
-                                    .map_or(false, |c| c.is_ascii_digit())
-                                    && trimmed.contains(". ")
-                                {
-                                    html.push_str("<ol class=\"space-y-2\">\n");
-                                    process_list_items(text, html, true);
-                                    html.push_str("</ol>\n");
-                                } else {
-                                    convert_node_to_html(
-                                        &child,
-                                        source,
-                                        html,
-                                        link_references,
-                                        current_list_key,
-                                        is_first_heading,
-                                        is_first_paragraph,
-                                    );
-                                }
-                            }
-                        }
+                    let is_more_marker = child
+                        .utf8_text(source.as_bytes())
+                        .is_ok_and(|text| text.trim() == MORE_MARKER);
+                    if is_more_marker && ctx.excerpt.is_none() {
+                        ctx.excerpt = Some(html[body_start..].to_string());
+                        continue;
+                    }
+
+                    let is_title = ctx.is_first_heading;
+                    convert_node_to_html(&child, source, html, ctx);
+                    if is_title && !ctx.is_first_heading {
+                        body_start = html.len();
                     }
                 }
-                i += 1;
             }
         }
 
@@ -328,8 +1487,8 @@ fn convert_node_to_html(
                 }
             }
 
-            if level == 1 && *is_first_heading {
-                *is_first_heading = false;
+            if level == 1 && ctx.is_first_heading {
+                ctx.is_first_heading = false;
 
                 let github_url = "https://github.com/Abraxas-365";
                 let linkedin_url =
@@ -340,7 +1499,7 @@ fn convert_node_to_html(
 
                 html.push_str(&format!(
                     "<h1 class=\"text-2xl text-gruvbox-yellow font-normal mt-8 mb-6 relative\">{}</h1>\n",
-                    heading_content
+                    math::restore_math(&heading_content, ctx.math_spans)
                 ));
 
                 html.push_str(&format!(
@@ -379,88 +1538,117 @@ fn convert_node_to_html(
 
                 html.push_str("</div>\n");
             } else {
-                html.push_str(&format!(
-                    "<h{} class=\"text-{} text-gruvbox-yellow font-normal mt-8 mb-6 relative\">",
-                    level,
-                    match level {
-                        1 => "2xl",
-                        2 => "xl",
-                        3 => "lg",
-                        _ => "base",
-                    }
-                ));
-
+                // Math stays a placeholder so the anchor and contents use its TeX source
+                let math_spans = std::mem::take(&mut ctx.math_spans);
+                let mut inner = String::new();
                 for i in 0..node.child_count() {
                     if let Some(child) = node.child(i) {
                         if child.kind() == "heading_content" {
                             for j in 0..child.child_count() {
                                 if let Some(content_child) = child.child(j) {
-                                    convert_node_to_html(
-                                        &content_child,
-                                        source,
-                                        html,
-                                        link_references,
-                                        current_list_key,
-                                        is_first_heading,
-                                        is_first_paragraph,
-                                    );
+                                    convert_node_to_html(&content_child, source, &mut inner, ctx);
                                 }
                             }
                         }
                     }
                 }
+                ctx.math_spans = math_spans;
+
+                // Anchor for the table of contents, numbered if the text repeats
+                let text = math::restore_source(&html_to_text(&inner), math_spans)
+                    .split_whitespace()
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                let inner = math::restore_math(&inner, math_spans);
+                let mut id = slugify(&text);
+                if id.is_empty() {
+                    id = "section".to_string();
+                }
+                let count = ctx.heading_ids.entry(id.clone()).or_insert(0);
+                *count += 1;
+                if *count > 1 {
+                    id = format!("{}-{}", id, count);
+                }
+
+                html.push_str(&format!(
+                    "<h{} id=\"{}\" class=\"text-{} text-gruvbox-yellow font-normal mt-8 mb-6 relative\">{}</h{}>\n",
+                    level,
+                    id,
+                    match level {
+                        1 => "2xl",
+                        2 => "xl",
+                        3 => "lg",
+                        _ => "base",
+                    },
+                    inner,
+                    level
+                ));
 
-                html.push_str(&format!("</h{}>\n", level));
+                ctx.toc.push(TocEntry { level, id, text });
             }
         }
         "paragraph" => {
-            if current_list_key.is_some() {
+            // Paragraphs inside tight list items are rendered inline in the <li>,
+            // loose list items keep their paragraph breaks
+            if let Some(list_key) = &ctx.current_list_key {
+                let is_loose = list_key == "loose_list";
+                if is_loose {
+                    html.push_str("<p class=\"my-2\">");
+                }
+                for i in 0..node.child_count() {
+                    if let Some(child) = node.child(i) {
+                        convert_node_to_html(&child, source, html, ctx);
+                    }
+                }
+                if is_loose {
+                    html.push_str("</p>");
+                }
                 return;
             }
 
-            if *is_first_paragraph && !*is_first_heading {
-                *is_first_paragraph = false;
-
-                let mut para_text = String::new();
-                if let Ok(text) = node.utf8_text(source.as_bytes()) {
-                    para_text = text.to_string();
-                } else {
-                    for i in 0..node.child_count() {
-                        if let Some(child) = node.child(i) {
-                            if let Ok(text) = child.utf8_text(source.as_bytes()) {
-                                para_text.push_str(text);
-                            }
-                        }
-                    }
+            if ctx.options.figures {
+                if let Some(image) = standalone_image(node, source) {
+                    ctx.figure_count += 1;
+                    let image = render_image(&image, source, ctx);
+                    html.push_str(&image.to_figure_html(ctx.figure_count));
+                    return;
                 }
+            }
 
-                html.push_str(&format!("<p class=\"cursor\">{}</p>\n", para_text));
-                html.push_str("<hr class=\"border-t border-gruvbox-gray my-8\">\n");
+            if let Some(block) = node
+                .utf8_text(source.as_bytes())
+                .ok()
+                .and_then(|text| math::display_block(text, ctx.math_spans))
+            {
+                html.push_str(&block);
                 return;
             }
 
-            html.push_str("<p class=\"my-4\">");
-
+            let mut para_text = String::new();
             for i in 0..node.child_count() {
                 if let Some(child) = node.child(i) {
-                    convert_node_to_html(
-                        &child,
-                        source,
-                        html,
-                        link_references,
-                        current_list_key,
-                        is_first_heading,
-                        is_first_paragraph,
-                    );
+                    convert_node_to_html(&child, source, &mut para_text, ctx);
                 }
             }
+            if ctx.first_paragraph.is_none() {
+                ctx.first_paragraph = Some(para_text.clone());
+            }
 
-            html.push_str("</p>\n");
+            if ctx.is_first_paragraph && !ctx.is_first_heading {
+                ctx.is_first_paragraph = false;
+
+                html.push_str(&format!("<p class=\"cursor\">{}</p>\n", para_text));
+                html.push_str("<hr class=\"border-t border-gruvbox-gray my-8\">\n");
+                return;
+            }
+
+            html.push_str(&format!("<p class=\"my-4\">{}</p>\n", para_text));
         }
         "link" => {
             let mut url = "";
             let mut text = "";
             let mut title = "";
+            let mut label = "";
 
             for i in 0..node.child_count() {
                 if let Some(child) = node.child(i) {
@@ -470,6 +1658,11 @@ fn convert_node_to_html(
                                 text = content;
                             }
                         }
+                        "link_label" => {
+                            if let Ok(content) = child.utf8_text(source.as_bytes()) {
+                                label = content;
+                            }
+                        }
                         "link_destination" => {
                             if let Ok(content) = child.utf8_text(source.as_bytes()) {
                                 url = content;
@@ -485,134 +1678,130 @@ fn convert_node_to_html(
                 }
             }
 
-            html.push_str(&format!(
-                r#"<a href="{}" title="{}" class="text-gruvbox-blue hover:text-gruvbox-aqua">{}</a>"#,
-                url,
-                title,
-                text
-            ));
-        }
-
-        "image" => {
-            let mut url = String::new();
-            let mut alt = String::new();
-            let mut title = String::new();
-            let mut width = None;
-            let mut height = None;
-            let mut classes = String::from("max-w-full h-auto my-4 rounded-lg shadow-lg");
-            let mut style = String::new();
-
-            if let Ok(raw_text) = node.utf8_text(source.as_bytes()) {
-                if let Some(url_start) = raw_text.find('(') {
-                    if let Some(url_end) = raw_text[url_start..].find(')') {
-                        url = raw_text[url_start + 1..url_start + url_end]
-                            .trim()
-                            .to_string();
+            // Reference-style links: [text][label] or shortcut [label]
+            if url.is_empty() {
+                let key = if label.is_empty() { text } else { label };
+                if let Some((destination, ref_title)) = ctx.link_references.get(&key.to_lowercase())
+                {
+                    url = destination;
+                    if title.is_empty() {
+                        title = ref_title;
                     }
                 }
+            }
 
-                if let Some(alt_start) = raw_text.find('[') {
-                    if let Some(alt_end) = raw_text[alt_start..].find(']') {
-                        alt = raw_text[alt_start + 1..alt_start + alt_end]
-                            .trim()
-                            .to_string();
-                    }
-                }
+            push_link(
+                html,
+                &resolve_link_url(url, ctx),
+                &math::restore_source(title, ctx.math_spans),
+                &math::restore_math(text, ctx.math_spans),
+            );
+        }
+
+        "link_reference_definition" => {}
 
-                if let Some(title_start) = raw_text.rfind('"') {
-                    if let Some(title_end) = raw_text[..title_start].rfind('"') {
-                        title = raw_text[title_end + 1..title_start].trim().to_string();
+        "uri_autolink" | "www_autolink" | "email_autolink" => {
+            if let Ok(text) = node.utf8_text(source.as_bytes()) {
+                let text = text.trim_start_matches('<').trim_end_matches('>');
+                let url = match node.kind() {
+                    "www_autolink" => format!("http://{}", text),
+                    "email_autolink" if !text.starts_with("mailto:") => {
+                        format!("mailto:{}", text)
                     }
-                }
+                    _ => text.to_string(),
+                };
+                push_link(html, &resolve_link_url(&url, ctx), "", &escape_html(text));
             }
+        }
 
-            let start_byte = node.start_byte();
-            let preceding_text = &source[..start_byte];
+        "strikethrough" => {
+            html.push_str(r#"<del class="line-through text-gruvbox-gray">"#);
 
-            if let Some(last_comment_start) = preceding_text.rfind("<!--") {
-                if let Some(comment_end) = preceding_text[last_comment_start..].find("-->") {
-                    let comment =
-                        &preceding_text[last_comment_start..last_comment_start + comment_end];
-                    let attrs = comment
-                        .trim_start_matches("<!--")
-                        .trim_end_matches("-->")
-                        .trim();
+            for i in 0..node.child_count() {
+                if let Some(child) = node.child(i) {
+                    convert_node_to_html(&child, source, html, ctx);
+                }
+            }
 
-                    let mut current_attr = String::new();
-                    let mut in_quotes = false;
+            html.push_str("</del>");
+        }
 
-                    let mut full_attrs = Vec::new();
-                    for c in attrs.chars() {
-                        match c {
-                            '"' => {
-                                current_attr.push(c);
-                                in_quotes = !in_quotes;
-                            }
-                            ' ' if !in_quotes => {
-                                if !current_attr.is_empty() {
-                                    full_attrs.push(current_attr.clone());
-                                    current_attr.clear();
-                                }
-                            }
-                            _ => current_attr.push(c),
-                        }
-                    }
-                    if !current_attr.is_empty() {
-                        full_attrs.push(current_attr);
-                    }
+        "tight_list" | "loose_list" => {
+            let is_ordered = node
+                .child(0)
+                .and_then(|item| item.child(0))
+                .and_then(|marker| marker.utf8_text(source.as_bytes()).ok())
+                .is_some_and(|marker| marker.starts_with(|c: char| c.is_ascii_digit()));
+            let tag = if is_ordered { "ol" } else { "ul" };
 
-                    for attr in full_attrs {
-                        if let Some((key, value)) = attr.split_once('=') {
-                            let key = key.trim();
-                            let value = value.trim();
+            html.push_str(&format!("<{} class=\"space-y-2\">\n", tag));
 
-                            match key {
-                                "width" => width = Some(value.trim_matches('"').to_string()),
-                                "height" => height = Some(value.trim_matches('"').to_string()),
-                                "class" => {
-                                    classes.push_str(&format!(" {}", value.trim_matches('"')))
-                                }
-                                "style" => {
-                                    if value.starts_with('"') && value.ends_with('"') {
-                                        style = value[1..value.len() - 1].to_string();
-                                    } else {
-                                        style = value.to_string();
-                                    }
-                                }
-                                "preset" => {
-                                    classes = match value.trim_matches('"') {
-                                        "avatar" => {
-                                            "w-32 h-32 rounded-full object-cover".to_string()
-                                        }
-                                        "banner" => "w-full h-64 object-cover".to_string(),
-                                        "thumbnail" => "w-48 h-48 object-cover rounded".to_string(),
-                                        _ => classes,
-                                    }
-                                }
-                                _ => debug!("Unknown image attribute: {}={}", key, value),
-                            }
-                        }
-                    }
+            let parent_list_key = ctx.current_list_key.replace(node.kind().to_string());
+            for i in 0..node.child_count() {
+                if let Some(child) = node.child(i) {
+                    convert_node_to_html(&child, source, html, ctx);
                 }
             }
+            ctx.current_list_key = parent_list_key;
 
-            let mut img_tag = format!(
-                r#"<img src="{}" alt="{}" title="{}" class="{}""#,
-                url, alt, title, classes
-            );
+            html.push_str(&format!("</{}>\n", tag));
+        }
 
-            if let Some(w) = width {
-                img_tag.push_str(&format!(r#" width="{}""#, w));
+        "list_item" | "task_list_item" => {
+            if node.kind() == "task_list_item" {
+                html.push_str(r#"<li class="task-list-item">"#);
+            } else {
+                html.push_str("<li>");
             }
-            if let Some(h) = height {
-                img_tag.push_str(&format!(r#" height="{}""#, h));
+
+            for i in 0..node.child_count() {
+                if let Some(child) = node.child(i) {
+                    convert_node_to_html(&child, source, html, ctx);
+                }
             }
-            if !style.is_empty() {
-                img_tag.push_str(&format!(r#" style="{}""#, style));
+
+            html.push_str("</li>\n");
+        }
+
+        "list_marker" => {}
+
+        "text" => {
+            // Text following an image may start with the tail of its attribute list
+            let start = if node.start_byte() >= attribute_list_start {
+                node.start_byte().max(attribute_list_end)
+            } else {
+                node.start_byte()
+            };
+
+            if let Some(text) = source.get(start..node.end_byte()) {
+                let text = math::restore_math(text, ctx.math_spans);
+                if ctx.figure_labels.is_empty() {
+                    html.push_str(&text);
+                } else {
+                    html.push_str(&resolve_figure_refs(&text, ctx.figure_labels));
+                }
             }
+        }
 
-            img_tag.push('>');
-            html.push_str(&img_tag);
+        "soft_line_break" => html.push('\n'),
+
+        "task_list_item_marker" => {
+            let checked = node
+                .utf8_text(source.as_bytes())
+                .is_ok_and(|marker| marker.to_lowercase().contains('x'));
+
+            html.push_str(&format!(
+                r#"<input type="checkbox" disabled{} class="mr-2 align-middle accent-gruvbox-green">"#,
+                if checked { " checked" } else { "" }
+            ));
+        }
+
+        "image" => {
+            html.push_str(&render_image(node, source, ctx).to_html());
+
+            if let Some(attribute_list) = image_attribute_list(node, source) {
+                ctx.attribute_list = attribute_list;
+            }
         }
 
         "strong_emphasis" | "emphasis" => {
@@ -632,15 +1821,7 @@ fn convert_node_to_html(
             for i in 0..node.child_count() {
                 if let Some(child) = node.child(i) {
                     if child.kind() != "emphasis_delimiter" {
-                        convert_node_to_html(
-                            &child,
-                            source,
-                            html,
-                            link_references,
-                            current_list_key,
-                            is_first_heading,
-                            is_first_paragraph,
-                        );
+                        convert_node_to_html(&child, source, html, ctx);
                     }
                 }
             }
@@ -679,13 +1860,18 @@ fn convert_node_to_html(
                 }
             }
 
+            if DIAGRAM_LANGUAGES.contains(&language) {
+                html.push_str(&ctx.diagram_cache.render(&code_content));
+                return;
+            }
+
+            if language == "math" {
+                html.push_str(&math::render_display_block(&code_content));
+                return;
+            }
+
             // Escape HTML special characters
-            let escaped_content = code_content
-                .replace("&", "&amp;")
-                .replace("<", "&lt;")
-                .replace(">", "&gt;")
-                .replace("\"", "&quot;")
-                .replace("'", "&#39;");
+            let escaped_content = escape_html(&code_content);
 
             html.push_str(&format!(
                 r#"<pre class="line-numbers"><code class="language-{}">{}</code></pre>"#,
@@ -694,21 +1880,56 @@ fn convert_node_to_html(
         }
 
         "block_quote" => {
+            if let Some((style, title, body_start)) = detect_callout(node, source, ctx.options) {
+                html.push_str(&format!(
+                    r#"<div class="callout border-l-4 border-gruvbox-{color} bg-gruvbox-bg rounded-r pl-4 pr-4 py-2 my-4">
+<p class="flex items-center font-bold text-gruvbox-{color}"><span class="mr-2">{icon}</span>{title}</p>
+"#,
+                    color = style.color,
+                    icon = style.icon,
+                    title = math::restore_math(&title, ctx.math_spans)
+                ));
+
+                for i in 0..node.child_count() {
+                    if let Some(child) = node.child(i) {
+                        if child.end_byte() <= body_start {
+                            continue;
+                        }
+
+                        // The marker shares its paragraph with the first lines of the body
+                        if child.start_byte() < body_start {
+                            let mut body = String::new();
+                            for j in 0..child.child_count() {
+                                if let Some(inline) = child.child(j) {
+                                    if inline.start_byte() > body_start
+                                        || (inline.start_byte() == body_start
+                                            && inline.kind() != "soft_line_break")
+                                    {
+                                        convert_node_to_html(&inline, source, &mut body, ctx);
+                                    }
+                                }
+                            }
+                            if !body.trim().is_empty() {
+                                html.push_str(&format!("<p class=\"my-2\">{}</p>\n", body));
+                            }
+                            continue;
+                        }
+
+                        convert_node_to_html(&child, source, html, ctx);
+                    }
+                }
+
+                html.push_str("</div>\n");
+                return;
+            }
+
             html.push_str(
                 r#"<blockquote class="border-l-4 border-gruvbox-gray pl-4 my-4 italic">"#,
             );
 
             for i in 0..node.child_count() {
                 if let Some(child) = node.child(i) {
-                    convert_node_to_html(
-                        &child,
-                        source,
-                        html,
-                        link_references,
-                        current_list_key,
-                        is_first_heading,
-                        is_first_paragraph,
-                    );
+                    convert_node_to_html(&child, source, html, ctx);
                 }
             }
 
@@ -719,19 +1940,11 @@ fn convert_node_to_html(
         _ => {
             if !is_list_node(node) {
                 if let Ok(text) = node.utf8_text(source.as_bytes()) {
-                    html.push_str(text);
+                    html.push_str(&math::restore_math(text, ctx.math_spans));
                 } else {
                     for i in 0..node.child_count() {
                         if let Some(child) = node.child(i) {
-                            convert_node_to_html(
-                                &child,
-                                source,
-                                html,
-                                link_references,
-                                current_list_key,
-                                is_first_heading,
-                                is_first_paragraph,
-                            );
+                            convert_node_to_html(&child, source, html, ctx);
                         }
                     }
                 }
@@ -739,26 +1952,9 @@ fn convert_node_to_html(
         }
     }
 }
-fn markdown_to_html(
-    node: &Node,
-    source: &str,
-    link_references: &HashMap<String, (String, String)>,
-) -> String {
+fn markdown_to_html(node: &Node, source: &str, ctx: &mut RenderContext) -> String {
     let mut html = String::new();
-    let mut is_first_heading = true;
-    let mut is_first_paragraph = true;
-    let mut current_list_key = None;
-
-    convert_node_to_html(
-        node,
-        source,
-        &mut html,
-        link_references,
-        &mut current_list_key,
-        &mut is_first_heading,
-        &mut is_first_paragraph,
-    );
-
+    convert_node_to_html(node, source, &mut html, ctx);
     html
 }
 
@@ -767,71 +1963,772 @@ fn is_list_node(node: &Node) -> bool {
     kind == "list" || kind == "list_item" || kind.contains("list_marker")
 }
 
-fn process_list_items(text: &str, html: &mut String, is_ordered: bool) {
-    for line in text.lines() {
-        let trimmed = line.trim();
-        if trimmed.is_empty() {
-            continue;
-        }
+/// Detects a GitHub-style `> [!KIND] Optional title` callout, returning its
+/// style, title and the byte offset where the callout body starts.
+fn detect_callout<'a>(
+    node: &Node,
+    source: &str,
+    options: &'a RenderOptions,
+) -> Option<(&'a CalloutStyle, String, usize)> {
+    let first_block = node.child(0)?;
+    if first_block.kind() != "paragraph" {
+        return None;
+    }
 
-        if is_ordered {
-            if let Some(pos) = trimmed.find(". ") {
-                let content = trimmed[pos + 2..].trim();
-                let formatted_content = format_inline_text(content);
-                html.push_str(&format!("<li>{}</li>\n", formatted_content));
-            }
-        } else {
-            if trimmed.starts_with("- ") || trimmed.starts_with("* ") {
-                let content = trimmed[2..].trim();
-                let formatted_content = format_inline_text(content);
-                html.push_str(&format!("<li>{}</li>\n", formatted_content));
-            }
+    let text = first_block.utf8_text(source.as_bytes()).ok()?;
+    let marker_line = text.lines().next()?;
+    let rest = marker_line.trim_start().strip_prefix("[!")?;
+    let (kind, custom_title) = rest.split_once(']')?;
+
+    let style = options.callouts.get(&kind.trim().to_lowercase())?;
+    let title = if custom_title.trim().is_empty() {
+        style.title.clone()
+    } else {
+        custom_title.trim().to_string()
+    };
+
+    Some((style, title, first_block.start_byte() + marker_line.len()))
+}
+
+/// Words of rendered HTML outside code, diagrams and formulas.
+fn prose_word_count(html: &str) -> usize {
+    html_to_text(&strip_elements(html, &["<pre", "<svg", "<math", "<code"]))
+        .split_whitespace()
+        .count()
+}
+
+/// Drops code blocks and inline SVG (e.g. diagrams) from rendered HTML, leaving the prose.
+fn strip_code(html: &str) -> String {
+    strip_elements(html, &["<pre", "<svg"])
+}
+
+/// Text of rendered HTML worth searching: no code, diagrams or formulas.
+pub fn searchable_text(html: &str) -> String {
+    html_to_text(&strip_elements(html, &["<pre", "<svg", "<math"]))
+}
+
+/// Drops the elements opened by `tags`, e.g. `<pre`, with their content.
+fn strip_elements(html: &str, tags: &[&'static str]) -> String {
+    let mut prose = String::with_capacity(html.len());
+    let mut rest = html;
+    loop {
+        let next = tags
+            .iter()
+            .filter_map(|tag| rest.find(tag).map(|start| (start, *tag)))
+            .min();
+        let Some((start, tag)) = next else {
+            prose.push_str(rest);
+            return prose;
+        };
+
+        prose.push_str(&rest[..start]);
+        let close = format!("</{}>", &tag[1..]);
+        match rest[start..].find(&close) {
+            Some(end) => rest = &rest[start + end + close.len()..],
+            None => return prose,
         }
     }
 }
 
-fn format_inline_text(text: &str) -> String {
-    let mut result = String::new();
-    let mut chars = text.chars().peekable();
-    let mut in_bold = false;
-    let mut in_italic = false;
-
-    while let Some(c) = chars.next() {
+/// Text content of an HTML fragment: tags dropped, basic entities decoded.
+pub fn html_to_text(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
         match c {
-            '*' => {
-                if chars.peek() == Some(&'*') {
-                    // Handle bold text
-                    chars.next(); // consume second *
-                    if in_bold {
-                        result.push_str("</strong>");
-                    } else {
-                        result.push_str("<strong class=\"font-bold\">");
-                    }
-                    in_bold = !in_bold;
-                } else {
-                    // Handle italic text
-                    if in_italic {
-                        result.push_str("</em>");
-                    } else {
-                        result.push_str("<em class=\"italic\">");
-                    }
-                    in_italic = !in_italic;
-                }
-            }
-            _ => result.push(c),
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            _ if !in_tag => text.push(c),
+            _ => {}
         }
     }
 
-    // Close any unclosed tags
-    if in_bold {
-        result.push_str("</strong>");
-    }
-    if in_italic {
-        result.push_str("</em>");
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Rewrites relative links, pointing links to other posts' markdown files at the
+/// URL those posts are served at.
+fn resolve_link_url(url: &str, ctx: &RenderContext) -> String {
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+
+    if path.ends_with(".md") && !path.contains("://") {
+        let linked_post = ctx
+            .post_dir
+            .join(path)
+            .canonicalize()
+            .ok()
+            .and_then(|file| ctx.posts.get_by_path(&file));
+
+        if let Some(post) = linked_post {
+            return format!("{}{}", post.href(), &url[path.len()..]);
+        }
     }
 
-    result
+    rewrite_relative_url(url, ctx.base_url)
+}
+
+/// Appends a link. `text` is HTML, `url` and `title` are escaped.
+fn push_link(html: &mut String, url: &str, title: &str, text: &str) {
+    html.push_str(&format!(
+        r#"<a href="{}" title="{}" class="text-gruvbox-blue hover:text-gruvbox-aqua">{}</a>"#,
+        escape_html(url),
+        escape_html(title),
+        text
+    ));
 }
 
 unsafe impl Send for Renderer {}
 unsafe impl Sync for Renderer {}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, ResponseError};
+
+    use super::*;
+    use crate::renderer::fixtures::{fixture, fixture_with};
+    use crate::renderer::options::PreviewOptions;
+
+    #[test]
+    fn callouts_render_with_their_kind_and_title() {
+        let renderer = fixture("callouts", &[]);
+
+        let content = renderer
+            .render_source(
+                "# Title\n\n> [!WARNING] Careful now\n> Body *text*\n\n> [!UNKNOWN]\n> Quote\n",
+                None,
+            )
+            .unwrap();
+        assert!(content.html.contains("class=\"callout"), "{}", content.html);
+        assert!(
+            content.html.contains("</span>Careful now</p>"),
+            "{}",
+            content.html
+        );
+        assert!(
+            content.html.contains("<p class=\"my-2\">Body <em"),
+            "{}",
+            content.html
+        );
+        assert!(content.html.contains("<blockquote"), "{}", content.html);
+    }
+
+    #[test]
+    fn metadata_comments_are_not_rendered() {
+        let renderer = fixture("metadata", &[]);
+
+        let content = renderer
+            .render_source(
+                "<!--\ntitle: Post\ndraft: true\n-->\n\n# Post\n\nText\n",
+                None,
+            )
+            .unwrap();
+        assert_eq!(content.metadata["draft"], "true");
+        assert!(!content.html.contains("draft"), "{}", content.html);
+        assert!(content.html.contains("Text"));
+    }
+
+    #[test]
+    fn word_count_and_reading_time_cover_prose_only() {
+        let renderer = fixture("word_count", &[]);
+
+        let content = renderer
+            .render_source(
+                "# Title\n\nOne two `three four` five $x + y = z$ six.\n\n```\nlet seven = eight;\n```\n",
+                None,
+            )
+            .unwrap();
+        assert_eq!(content.word_count, 5, "{}", content.html);
+        assert_eq!(content.reading_time, 1);
+
+        let words = "word ".repeat(401);
+        let content = renderer.render_source(&words, None).unwrap();
+        assert_eq!(content.word_count, 401);
+        assert_eq!(content.reading_time, 3);
+
+        let content = renderer.render_source("", None).unwrap();
+        assert_eq!(content.reading_time, 0);
+    }
+
+    #[test]
+    fn excerpts_end_at_the_more_marker() {
+        let renderer = fixture("excerpts", &[]);
+        let excerpt = |source: &str| renderer.render_source(source, None).unwrap().excerpt;
+
+        assert_eq!(
+            excerpt("# Title\n\nFirst paragraph.\n\nSecond paragraph.\n"),
+            "First paragraph."
+        );
+        assert_eq!(
+            excerpt("# Title\n\nFirst.\n\nSecond.\n\n<!-- more -->\n\nThird.\n"),
+            "First. Second."
+        );
+        assert_eq!(
+            excerpt("<!--\ntitle: Title\n-->\n<!-- more -->\n\nBody.\n"),
+            ""
+        );
+        let content = renderer
+            .render_source("<!--\ntitle: Title\n-->\n<!-- more -->\n\nBody.\n", None)
+            .unwrap();
+        assert_eq!(content.metadata["title"], "Title");
+        assert!(!content.html.contains("more"), "{}", content.html);
+        assert!(content.html.contains("Body."));
+    }
+
+    #[test]
+    fn math_in_image_attributes_uses_tex_source() {
+        let renderer = fixture("math_alt", &[]);
+
+        let content = renderer
+            .render_source(
+                "# Title\n\nText\n\n![alt $x^2$](a.png \"title $y$\")\n",
+                None,
+            )
+            .unwrap();
+        assert!(
+            content.html.contains(r#"alt="alt x^2""#),
+            "{}",
+            content.html
+        );
+        assert!(
+            content.html.contains(r#"title="title y""#),
+            "{}",
+            content.html
+        );
+    }
+
+    #[test]
+    fn math_in_headings_uses_tex_source_for_anchors() {
+        let renderer = fixture("math_heading", &[]);
+
+        let content = renderer
+            .render_source("# Title\n\n## Euler $e^{i\\pi}$ identity\n", None)
+            .unwrap();
+        assert_eq!(content.toc[0].text, "Euler e^{i\\pi} identity");
+        assert_eq!(content.toc[0].id, "euler-e-i-pi-identity");
+        assert!(content.html.contains("<msup>"), "{}", content.html);
+        assert!(!content.html.contains('\u{E000}'));
+    }
+
+    #[test]
+    fn dollars_in_code_and_link_destinations_are_not_math() {
+        let renderer = fixture("math_verbatim", &[]);
+
+        let content = renderer
+            .render_source(
+                "# Title\n\n[cost](http://x.com/$a$b) `$c$`\n\n    indented $q$\n",
+                None,
+            )
+            .unwrap();
+        assert!(
+            content.html.contains(r#"href="http://x.com/$a$b""#),
+            "{}",
+            content.html
+        );
+        assert!(content.html.contains("$c$"));
+        assert!(content.html.contains("$q$"));
+        assert!(!content.html.contains("<math"));
+    }
+
+    #[test]
+    fn display_math_paragraphs_are_math_blocks() {
+        let renderer = fixture("math_display", &[]);
+
+        let content = renderer
+            .render_source("# Title\n\nText\n\n$$\nx^2\n$$\n\n```math\ny\n```\n", None)
+            .unwrap();
+        assert_eq!(content.html.matches("<div class=\"math-display").count(), 2);
+        assert!(!content.html.contains("<p class=\"my-4\"><math"));
+    }
+
+    #[test]
+    fn rendering_past_the_deadline_is_unavailable() {
+        let renderer = fixture("render_deadline", &[]);
+
+        let result = renderer.render_source("# Title\n\nText\n", Some(Instant::now()));
+        assert!(matches!(result, Err(ApiError::ServiceUnavailable(_))));
+    }
+
+    #[test]
+    fn content_pages_use_the_template() {
+        let renderer = fixture("render_page", &[]);
+
+        let content = renderer
+            .render_source("<!--\ntitle: Preview\n-->\n# Title\n\nBody text\n", None)
+            .unwrap();
+        let page = renderer.render_content_page(content).unwrap();
+        assert!(page.starts_with("<title>Preview</title>"), "{}", page);
+        assert!(page.contains("Body text"));
+    }
+
+    #[test]
+    fn search_index_follows_post_edits() {
+        let source = |body: &str| {
+            format!(
+                "<!--\ntitle: Post\ndate: 2024-01-02\n-->\n# Post\n\n{}\n",
+                body
+            )
+        };
+        let renderer = fixture("search_edits", &[("post.md", &source("walrus"))]);
+        let hits = |word: &str| {
+            renderer
+                .search(&SearchQuery::new(word, None))
+                .unwrap()
+                .len()
+        };
+        assert_eq!(hits("walrus"), 1);
+
+        renderer
+            .save_post("post", &source("narwhal"), &Precondition::None)
+            .unwrap();
+        assert_eq!(hits("walrus"), 0);
+        assert_eq!(hits("narwhal"), 1);
+
+        renderer.delete_post("post", &Precondition::None).unwrap();
+        assert_eq!(hits("narwhal"), 0);
+    }
+
+    #[test]
+    fn searches_do_not_rebuild_the_index() {
+        let renderer = fixture("search_refresh", &[("old.md", "# Old\n\nWalrus\n")]);
+        let hits = |word: &str| {
+            renderer
+                .search(&SearchQuery::new(word, None))
+                .unwrap()
+                .len()
+        };
+        assert!(!renderer.refresh_changed_posts().unwrap());
+
+        // Searches don't rebuild even once a throttled rebuild would be due, a post
+        // added outside the API is found once a refresh notices it
+        std::thread::sleep(POST_INDEX_REFRESH_INTERVAL);
+        fs::write(renderer.base_path.join("new.md"), "# New\n\nNarwhal\n").unwrap();
+        assert_eq!(hits("narwhal"), 0);
+        assert!(renderer.refresh_changed_posts().unwrap());
+        assert_eq!(hits("narwhal"), 1);
+        assert!(!renderer.refresh_changed_posts().unwrap());
+
+        fs::remove_file(renderer.base_path.join("old.md")).unwrap();
+        assert!(renderer.refresh_changed_posts().unwrap());
+        assert_eq!(hits("walrus"), 0);
+    }
+
+    #[test]
+    fn search_skips_code_diagrams_and_math() {
+        let renderer = fixture(
+            "search_text",
+            &[(
+                "post.md",
+                "<!--\ntitle: Post\n-->\n# Post\n\nProse $zebra$\n\n```\nkoala\n```\n",
+            )],
+        );
+        let hits = |word: &str| {
+            renderer
+                .search(&SearchQuery::new(word, None))
+                .unwrap()
+                .len()
+        };
+
+        assert_eq!(hits("prose"), 1);
+        assert_eq!(hits("zebra"), 0);
+        assert_eq!(hits("koala"), 0);
+    }
+
+    #[test]
+    fn related_posts_are_updated_with_the_index() {
+        let source = |tags: &str, body: &str| {
+            format!(
+                "<!--\ntitle: {}\ndate: 2024-01-02\ntags: {}\n-->\n# Post\n\n{}\n",
+                body, tags, body
+            )
+        };
+        let renderer = fixture(
+            "related",
+            &[
+                ("a.md", &source("rust, parsing", "Lexers")),
+                ("b.md", &source("rust, parsing", "Grammars")),
+                ("c.md", &source("cooking", "Bread")),
+            ],
+        );
+        let related = |url: &str| {
+            let post = renderer.find_post(url).unwrap().unwrap();
+            let navigation = renderer.navigation(&post).unwrap();
+            navigation
+                .related
+                .iter()
+                .map(|post| post.url.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(related("a"), vec!["b".to_string()]);
+        assert!(related("c").is_empty());
+
+        renderer
+            .save_post("c", &source("rust, parsing", "Bread"), &Precondition::None)
+            .unwrap();
+        assert_eq!(related("a").len(), 2);
+        assert!(related("c").contains(&"a".to_string()));
+    }
+
+    #[test]
+    fn series_are_grouped_with_the_post_index() {
+        let part = |order: u32, extra: &str| {
+            format!(
+                "<!--\ntitle: Part {}\ndate: 2024-01-0{}\nseries: Parsers\nseries_order: {}\n{}-->\n# Part\n",
+                order, order, order, extra
+            )
+        };
+        let renderer = fixture(
+            "series_index",
+            &[
+                ("one.md", &part(1, "")),
+                ("two.md", &part(2, "draft: true\n")),
+            ],
+        );
+        let titles = |series: &Series| {
+            series
+                .parts
+                .iter()
+                .map(|part| part.title().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        // Drafts are grouped but not shown
+        assert_eq!(titles(&renderer.series("parsers").unwrap()), vec!["Part 1"]);
+        let draft = renderer.find_post("two").unwrap().unwrap();
+        assert!(renderer.series_of(&draft).unwrap().is_none());
+
+        renderer
+            .save_post("three", &part(3, ""), &Precondition::None)
+            .unwrap();
+        let series = renderer.all_series().unwrap();
+        assert_eq!(series.len(), 1);
+        assert_eq!(titles(&series[0]), vec!["Part 1", "Part 3"]);
+    }
+
+    #[test]
+    fn strikethrough_and_task_lists() {
+        let renderer = fixture("gfm_inline", &[]);
+
+        let html = renderer
+            .render_source("# T\n\n~~gone~~ kept\n\n- [ ] todo\n- [x] done\n", None)
+            .unwrap()
+            .html;
+        assert!(
+            html.contains(r#"<del class="line-through text-gruvbox-gray">gone</del> kept"#),
+            "{}",
+            html
+        );
+        assert_eq!(html.matches(r#"<li class="task-list-item">"#).count(), 2);
+        assert_eq!(
+            html.matches(r#"<input type="checkbox" disabled class"#)
+                .count(),
+            1
+        );
+        assert_eq!(
+            html.matches(r#"<input type="checkbox" disabled checked"#)
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn autolinks_are_escaped_links() {
+        let renderer = fixture("autolinks", &[]);
+
+        let html = renderer
+            .render_source(
+                "# T\n\nSee <https://example.com/a?b=1&c=2> and www.example.org today\n",
+                None,
+            )
+            .unwrap()
+            .html;
+        assert!(
+            html.contains(r#"<a href="https://example.com/a?b=1&amp;c=2" title="" class="text-gruvbox-blue hover:text-gruvbox-aqua">https://example.com/a?b=1&amp;c=2</a>"#),
+            "{}",
+            html
+        );
+        assert!(
+            html.contains(r#"href="http://www.example.org""#),
+            "{}",
+            html
+        );
+        assert!(html.contains(">www.example.org</a>"));
+    }
+
+    fn figure_options() -> RenderOptions {
+        RenderOptions {
+            figures: true,
+            ..RenderOptions::default()
+        }
+    }
+
+    #[test]
+    fn figures_are_numbered_and_referenced() {
+        let renderer = fixture_with("figures", &[], figure_options());
+
+        let html = renderer
+            .render_source(
+                "# T\n\nAs {fig:second} shows, not {fig:missing}.\n\n![a](a.png){#first}\n\nInline ![b](b.png) image\n\n![c](c.png){#second}\n",
+                None,
+            )
+            .unwrap()
+            .html;
+        assert!(
+            html.contains(r##"<a href="#fig-second" class="text-gruvbox-blue hover:text-gruvbox-aqua">Figure 2</a> shows"##),
+            "{}",
+            html
+        );
+        assert!(html.contains("not {fig:missing}."));
+        assert!(html.contains(r#"<figure id="fig-first""#));
+        assert!(html.contains(r#"<figure id="fig-second""#));
+        assert!(html.contains("Figure 1.</span>"));
+        assert!(html.contains("Figure 2.</span>"));
+        assert_eq!(html.matches("<figure").count(), 2);
+    }
+
+    #[test]
+    fn figure_captions_and_labels_are_escaped() {
+        let renderer = fixture_with("figure_escaping", &[], figure_options());
+
+        let html = renderer
+            .render_source(
+                "# T\n\n![a](a.png \"Cost < 5\"){#a&b}\n\n![b](b.png){caption=\"A & B <i>\"}\n",
+                None,
+            )
+            .unwrap()
+            .html;
+        assert!(
+            html.contains("</span> Cost &lt; 5</figcaption>"),
+            "{}",
+            html
+        );
+        assert!(
+            html.contains("</span> A &amp; B &lt;i&gt;</figcaption>"),
+            "{}",
+            html
+        );
+        assert!(html.contains(r#"<figure id="fig-a&amp;b""#), "{}", html);
+        assert!(!html.contains("<i>"));
+    }
+
+    #[test]
+    fn image_urls_with_parentheses_and_alt_with_brackets() {
+        let renderer = fixture("image_syntax", &[]);
+
+        let html = renderer
+            .render_source(
+                "# T\n\n![a [b] c](https://example.com/Foo_(bar).png \"t\")\n",
+                None,
+            )
+            .unwrap()
+            .html;
+        assert!(
+            html.contains(
+                r#"<img src="https://example.com/Foo_(bar).png" alt="a [b] c" title="t""#
+            ),
+            "{}",
+            html
+        );
+    }
+
+    #[test]
+    fn comments_before_an_image_are_not_its_attributes() {
+        let renderer = fixture("image_comments", &[]);
+
+        let html = renderer
+            .render_source(
+                "<!--\ntitle: T\nwidth: 50\n-->\n# T\n\n<!-- width=60 class=evil -->\n\n![a](a.png)\n",
+                None,
+            )
+            .unwrap()
+            .html;
+        let img = &html[html.find("<img").unwrap()..];
+        let img = &img[..img.find('>').unwrap()];
+        assert!(!img.contains("width"), "{}", img);
+        assert!(!img.contains("evil"), "{}", img);
+    }
+
+    #[test]
+    fn image_attribute_lists_apply_to_their_image() {
+        let renderer = fixture_with("image_attributes", &[], figure_options());
+
+        let html = renderer
+            .render_source(
+                "# T\n\n![a](a.png){width=300 height=\"200\" .wide #hero style=\"border: 0\"}\n\nText ![b](b.png) after {width=10}\n\n![c](c.png){preset=avatar}\n",
+                None,
+            )
+            .unwrap()
+            .html;
+        assert!(html.contains(r#"<figure id="fig-hero""#), "{}", html);
+        assert!(
+            html.contains(r#"class="max-w-full h-auto my-4 rounded-lg shadow-lg wide" width="300" height="200" style="border: 0">"#),
+            "{}",
+            html
+        );
+        assert!(html
+            .contains(r#"alt="b" title="" class="max-w-full h-auto my-4 rounded-lg shadow-lg">"#));
+        assert!(html.contains("after {width=10}"));
+        assert!(html.contains(r#"class="w-32 h-32 rounded-full object-cover">"#));
+        assert!(!html.contains("{width=300"));
+    }
+
+    #[test]
+    fn image_attribute_values_are_escaped() {
+        let renderer = fixture("image_attribute_escaping", &[]);
+
+        let html = renderer
+            .render_source(
+                "# T\n\n![a](a.png){width=1>2 height=3&4 .x\"onload=alert(1)\" style=\"a<b\"}\n",
+                None,
+            )
+            .unwrap()
+            .html;
+        assert!(
+            html.contains(r#"x&quot;onload=alert(1)&quot;""#),
+            "{}",
+            html
+        );
+        assert!(html.contains(r#"width="1&gt;2" height="3&amp;4" style="a&lt;b">"#));
+        assert!(!html.contains("onload=alert(1)\""));
+    }
+
+    #[test]
+    fn responsive_image_attributes_are_escaped() {
+        let image = Image {
+            url: "a.png".to_string(),
+            alt: String::new(),
+            title: String::new(),
+            width: None,
+            height: None,
+            classes: String::new(),
+            style: String::new(),
+            caption: None,
+            label: None,
+            responsive: Some(ResponsiveImage {
+                width: 10,
+                height: 10,
+                src: "/images/a\"b.png".to_string(),
+                srcset: "/images/a\"b.png 10w".to_string(),
+                webp_srcset: "/images/a<b.webp 10w".to_string(),
+                sizes: "100vw\"".to_string(),
+            }),
+        };
+
+        let html = image.to_html();
+        assert!(html.contains(r#"src="/images/a&quot;b.png""#), "{}", html);
+        assert!(html.contains(r#"srcset="/images/a&quot;b.png 10w" sizes="100vw&quot;""#));
+        assert!(html.contains(r#"srcset="/images/a&lt;b.webp 10w""#));
+    }
+
+    #[test]
+    fn drafts_and_scheduled_posts_are_hidden_and_unlisted_posts_unlisted() {
+        let post = |extra: &str| {
+            format!(
+                "<!--\ntitle: Post\ntags: walrus\n{}-->\n# Post\n\nA story about walruses.\n",
+                extra
+            )
+        };
+        let renderer = fixture(
+            "visibility",
+            &[
+                ("older.md", &post("date: 2024-01-01\n")),
+                ("listed.md", &post("date: 2024-01-02\n")),
+                ("unlisted.md", &post("date: 2024-01-03\nunlisted: true\n")),
+                ("draft.md", &post("date: 2024-01-04\ndraft: true\n")),
+                ("future.md", &post("date: 2999-01-01\n")),
+            ],
+        );
+        let urls = |posts: &[Post]| {
+            posts
+                .iter()
+                .map(|post| post.url.as_str())
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+
+        for hidden in ["draft", "future"] {
+            let status = renderer
+                .route(hidden, Preview::None)
+                .err()
+                .map(|error| error.status_code());
+            assert_eq!(status, Some(StatusCode::NOT_FOUND), "{}", hidden);
+            assert!(renderer.route(hidden, Preview::All).is_ok());
+        }
+        assert!(matches!(
+            renderer.route("unlisted", Preview::None),
+            Ok(Route::Post(post)) if post.url == "unlisted"
+        ));
+
+        assert_eq!(urls(&renderer.listed_posts().unwrap()), "listed older");
+        let hits: Vec<Post> = renderer
+            .search(&SearchQuery::new("walruses", None))
+            .unwrap()
+            .into_iter()
+            .map(|hit| hit.post)
+            .collect();
+        let mut found: Vec<&str> = hits.iter().map(|post| post.url.as_str()).collect();
+        found.sort();
+        assert_eq!(found, vec!["listed", "older"]);
+
+        let listed = renderer.find_post("listed").unwrap().unwrap();
+        let navigation = renderer.navigation(&listed).unwrap();
+        assert_eq!(
+            navigation.prev.map(|post| post.url).as_deref(),
+            Some("older")
+        );
+        assert!(navigation.next.is_none());
+        assert_eq!(urls(&navigation.related), "older");
+
+        let unlisted = renderer.find_post("unlisted").unwrap().unwrap();
+        let navigation = renderer.navigation(&unlisted).unwrap();
+        assert!(navigation.prev.is_none() && navigation.next.is_none());
+        assert!(navigation.related.is_empty());
+    }
+
+    #[test]
+    fn preview_links_serve_only_their_draft() {
+        let draft = "<!--\ntitle: Draft\ndraft: true\n-->\n# Draft\n";
+        let posts = [("draft.md", draft), ("other.md", draft)];
+        let options = RenderOptions {
+            preview: PreviewOptions {
+                secret: Some("secret".to_string()),
+                ..PreviewOptions::default()
+            },
+            ..RenderOptions::default()
+        };
+        let renderer = fixture_with("preview_links", &posts, options);
+        let status = |url: &str, token: &str| {
+            renderer
+                .route(url, Preview::Token(token))
+                .err()
+                .map(|error| error.status_code())
+        };
+
+        let link = renderer.preview_url("draft").unwrap();
+        let token = link.strip_prefix("/blog/draft?preview=").unwrap();
+        assert_eq!(status("draft", token), None);
+        assert_eq!(status("other", token), Some(StatusCode::FORBIDDEN));
+        let expired = preview::sign("secret", "draft", Utc::now().timestamp() - 1);
+        assert_eq!(status("draft", &expired), Some(StatusCode::UNAUTHORIZED));
+
+        // Without a secret no token is accepted, and none can be created
+        let renderer = fixture("preview_disabled", &posts);
+        let status = renderer
+            .route("draft", Preview::Token(token))
+            .err()
+            .map(|error| error.status_code());
+        assert_eq!(status, Some(StatusCode::FORBIDDEN));
+        assert!(renderer.preview_url("draft").is_err());
+    }
+}
@@ -0,0 +1,141 @@
+// Search widget for statically exported blogs. Searches the JSON index written by
+// `md_to_html export` in the browser, ranking matches like the server does.
+(function () {
+    var TITLE_WEIGHT = 5, TAG_WEIGHT = 4, HEADING_WEIGHT = 3, BODY_WEIGHT = 1;
+    var PREFIX_FACTOR = 0.5;
+
+    var root = document.querySelector("[data-search-index]");
+    if (!root) {
+        return;
+    }
+    var input = root.querySelector("input[name=q]");
+    var results = root.querySelector("[data-search-results]");
+    var params = new URLSearchParams(window.location.search);
+    var tags = (params.get("tag") || "").split(",").map(function (tag) {
+        return tag.trim().toLowerCase();
+    }).filter(Boolean);
+    var documents = null;
+
+    function tokenize(text) {
+        return text.toLowerCase().split(/[^\p{L}\p{N}]+/u).filter(Boolean);
+    }
+
+    function escapeHtml(text) {
+        return text.replace(/&/g, "&amp;").replace(/</g, "&lt;").replace(/>/g, "&gt;")
+            .replace(/"/g, "&quot;").replace(/'/g, "&#39;");
+    }
+
+    function load() {
+        if (documents) {
+            return Promise.resolve(documents);
+        }
+        return fetch(root.getAttribute("data-search-index"))
+            .then(function (response) { return response.json(); })
+            .then(function (index) {
+                documents = index.documents.map(function (doc) {
+                    doc.fields = [
+                        [tokenize(doc.title), TITLE_WEIGHT],
+                        [tokenize(doc.tags.join(" ")), TAG_WEIGHT],
+                        [tokenize(doc.headings.join(" ")), HEADING_WEIGHT],
+                        [doc.terms.split(" "), BODY_WEIGHT]
+                    ];
+                    return doc;
+                });
+                return documents;
+            });
+    }
+
+    // Every query word must match a word of the post, exactly or as a prefix
+    function score(doc, terms) {
+        var total = 0;
+        for (var i = 0; i < terms.length; i++) {
+            var termScore = 0;
+            doc.fields.forEach(function (field) {
+                field[0].forEach(function (word) {
+                    if (word === terms[i]) {
+                        termScore += field[1];
+                    } else if (word.indexOf(terms[i]) === 0) {
+                        termScore += field[1] * PREFIX_FACTOR;
+                    }
+                });
+            });
+            if (termScore === 0) {
+                return 0;
+            }
+            total += termScore;
+        }
+        return total;
+    }
+
+    function hasTags(doc) {
+        return tags.every(function (tag) {
+            return doc.tags.some(function (postTag) { return postTag.toLowerCase() === tag; });
+        });
+    }
+
+    function render(query, docs) {
+        var terms = tokenize(query);
+        if (terms.length === 0) {
+            results.innerHTML = "";
+            return;
+        }
+
+        var hits = docs
+            .filter(hasTags)
+            .map(function (doc) { return { doc: doc, score: score(doc, terms) }; })
+            .filter(function (hit) { return hit.score > 0; })
+            .sort(function (a, b) {
+                return b.score - a.score || (b.doc.date || "").localeCompare(a.doc.date || "");
+            });
+
+        if (hits.length === 0) {
+            results.innerHTML = '<p class="my-4 text-gruvbox-fg-dim">No posts match <strong>'
+                + escapeHtml(query) + "</strong>.</p>";
+            return;
+        }
+
+        results.innerHTML = "<ul>" + hits.map(function (hit) {
+            var doc = hit.doc;
+            var date = doc.date
+                ? ' <span class="text-sm text-gruvbox-gray">' + doc.date.slice(0, 10) + "</span>"
+                : "";
+            return '<li class="mb-6"><a href="' + escapeHtml(doc.url)
+                + '" class="text-gruvbox-blue hover:text-gruvbox-aqua">' + escapeHtml(doc.title)
+                + "</a>" + date + '\n<p class="text-sm text-gruvbox-fg-dim">' + doc.excerpt
+                + "</p></li>";
+        }).join("\n") + "</ul>";
+    }
+
+    function search() {
+        var query = input.value;
+        var url = new URL(window.location.href);
+        if (query) {
+            url.searchParams.set("q", query);
+        } else {
+            url.searchParams.delete("q");
+        }
+        window.history.replaceState(null, "", url);
+
+        load().then(function (docs) {
+            // Skip stale results if the query changed while the index loaded
+            if (input.value === query) {
+                render(query, docs);
+            }
+        });
+    }
+
+    var timer = null;
+    input.addEventListener("input", function () {
+        clearTimeout(timer);
+        timer = setTimeout(search, 150);
+    });
+    root.querySelector("form").addEventListener("submit", function (event) {
+        event.preventDefault();
+        search();
+    });
+
+    input.value = params.get("q") || "";
+    if (input.value) {
+        search();
+    }
+})();
@@ -0,0 +1,565 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use serde::Serialize;
+
+use super::{escape_html, posts::Post, searchable_text, RenderedContent, BLOG_URL_PREFIX};
+
+// Matches in titles and tags count more than in headings, which count more than body text
+const TITLE_WEIGHT: f64 = 5.0;
+const TAG_WEIGHT: f64 = 4.0;
+const HEADING_WEIGHT: f64 = 3.0;
+const BODY_WEIGHT: f64 = 1.0;
+/// Score factor of a term that only starts with a query word
+const PREFIX_FACTOR: f64 = 0.5;
+
+/// Weight of the tag overlap of two posts against the similarity of their text
+const RELATED_TAG_WEIGHT: f64 = 1.0;
+/// Posts scoring lower are not considered related
+const MIN_RELATED_SCORE: f64 = 0.1;
+
+/// Characters of body text shown around the first match
+const SNIPPET_LENGTH: usize = 160;
+
+/// Browser side of static search, see `static_search_page_html`
+pub const WIDGET_JS: &str = include_str!("search.js");
+
+/// The searchable text of a post, built from its rendered content.
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchDocument {
+    pub url: String,
+    pub title: String,
+    pub headings: Vec<String>,
+    pub tags: Vec<String>,
+    pub date: Option<String>,
+    /// Body text without markup, code blocks, diagrams or formulas
+    pub text: String,
+    pub excerpt: String,
+}
+
+impl SearchDocument {
+    pub fn new(post: &Post, content: &RenderedContent) -> Self {
+        SearchDocument {
+            url: post.href(),
+            title: post.title().to_string(),
+            headings: content.toc.iter().map(|entry| entry.text.clone()).collect(),
+            tags: post.tags(),
+            date: post.date().map(|date| date.to_rfc3339()),
+            text: searchable_text(&content.html)
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" "),
+            excerpt: content.excerpt.clone(),
+        }
+    }
+}
+
+/// Search index written by static exports, see `static_index`.
+#[derive(Serialize)]
+pub struct StaticSearchIndex {
+    pub documents: Vec<StaticDocument>,
+}
+
+#[derive(Serialize)]
+pub struct StaticDocument {
+    pub url: String,
+    pub title: String,
+    pub headings: Vec<String>,
+    pub tags: Vec<String>,
+    pub date: Option<String>,
+    /// Escaped excerpt, shown in results
+    pub excerpt: String,
+    /// Distinct words of the body text, space-separated
+    pub terms: String,
+}
+
+impl From<&SearchDocument> for StaticDocument {
+    fn from(document: &SearchDocument) -> Self {
+        let mut terms = tokenize(&document.text);
+        terms.sort();
+        terms.dedup();
+
+        StaticDocument {
+            url: document.url.clone(),
+            title: document.title.clone(),
+            headings: document.headings.clone(),
+            tags: document.tags.clone(),
+            date: document.date.clone(),
+            excerpt: escape_html(&document.excerpt),
+            terms: terms.join(" "),
+        }
+    }
+}
+
+/// A search, split into words.
+pub struct SearchQuery {
+    pub terms: Vec<String>,
+    /// Results must have all of these tags
+    pub tags: Vec<String>,
+}
+
+impl SearchQuery {
+    /// `tags` is a comma-separated list.
+    pub fn new(text: &str, tags: Option<&str>) -> Self {
+        let mut terms = tokenize(text);
+        terms.sort();
+        terms.dedup();
+        let tags = tags
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|tag| !tag.is_empty())
+            .map(str::to_string)
+            .collect();
+        SearchQuery { terms, tags }
+    }
+}
+
+pub struct SearchHit {
+    pub post: Post,
+    pub score: f64,
+    /// HTML excerpt of the body with matches in `<mark>`
+    pub snippet: String,
+}
+
+/// How often a term occurs in each part of a document.
+#[derive(Default, Clone, Copy)]
+struct Occurrences {
+    title: u32,
+    tags: u32,
+    headings: u32,
+    body: u32,
+}
+
+impl Occurrences {
+    fn weight(&self) -> f64 {
+        self.title as f64 * TITLE_WEIGHT
+            + self.tags as f64 * TAG_WEIGHT
+            + self.headings as f64 * HEADING_WEIGHT
+            + (1.0 + self.body as f64).ln() * BODY_WEIGHT
+    }
+}
+
+struct IndexedPost {
+    post: Post,
+    document: SearchDocument,
+    modified: Option<SystemTime>,
+    /// Weighted term frequencies, for text similarity
+    weights: HashMap<String, f64>,
+}
+
+/// Inverted index from terms to the posts containing them. Posts are keyed by
+/// their markdown file and re-indexed only when that file changes.
+#[derive(Default)]
+pub struct SearchIndex {
+    posts: HashMap<PathBuf, IndexedPost>,
+    /// Sorted, so terms starting with a prefix are a contiguous range
+    terms: BTreeMap<String, HashMap<PathBuf, Occurrences>>,
+    /// Most similar posts of each post, see `update_related`
+    related: HashMap<PathBuf, Vec<Post>>,
+}
+
+impl SearchIndex {
+    /// Whether the post needs (re-)indexing after its file was last modified at `modified`.
+    pub fn is_stale(&self, post: &Post, modified: Option<SystemTime>) -> bool {
+        self.posts
+            .get(&post.path)
+            .is_none_or(|indexed| indexed.modified != modified || indexed.post.url != post.url)
+    }
+
+    pub fn insert(&mut self, post: Post, document: SearchDocument, modified: Option<SystemTime>) {
+        self.remove(&post.path);
+
+        let mut occurrences: HashMap<String, Occurrences> = HashMap::new();
+        let mut count = |text: &str, field: fn(&mut Occurrences) -> &mut u32| {
+            for term in tokenize(text) {
+                *field(occurrences.entry(term).or_default()) += 1;
+            }
+        };
+        count(&document.title, |o| &mut o.title);
+        for tag in &document.tags {
+            count(tag, |o| &mut o.tags);
+        }
+        for heading in &document.headings {
+            count(heading, |o| &mut o.headings);
+        }
+        count(&document.text, |o| &mut o.body);
+
+        let mut weights = HashMap::new();
+        for (term, occurrences) in occurrences {
+            weights.insert(term.clone(), occurrences.weight());
+            self.terms
+                .entry(term)
+                .or_default()
+                .insert(post.path.clone(), occurrences);
+        }
+
+        self.posts.insert(
+            post.path.clone(),
+            IndexedPost {
+                post,
+                document,
+                modified,
+                weights,
+            },
+        );
+    }
+
+    pub fn remove(&mut self, path: &Path) {
+        if self.posts.remove(path).is_none() {
+            return;
+        }
+        self.terms.retain(|_, postings| {
+            postings.remove(path);
+            !postings.is_empty()
+        });
+    }
+
+    /// Indexed documents, newest first.
+    pub fn documents(&self) -> Vec<SearchDocument> {
+        let mut posts: Vec<&IndexedPost> = self.posts.values().collect();
+        posts.sort_by_key(|indexed| std::cmp::Reverse(indexed.post.date()));
+        posts
+            .into_iter()
+            .map(|indexed| indexed.document.clone())
+            .collect()
+    }
+
+    /// Posts most similar to the one at `path`, best first, as of the last
+    /// `update_related`.
+    pub fn related(&self, path: &Path) -> Vec<Post> {
+        self.related.get(path).cloned().unwrap_or_default()
+    }
+
+    /// Finds the up to `limit` posts most similar to each post, by shared tags and the
+    /// cosine similarity of their TF-IDF weighted words. This compares every pair of
+    /// posts, so it runs once after the index changed rather than per page.
+    pub fn update_related(&mut self, limit: usize) {
+        let norm = |vector: &HashMap<&str, f64>| vector.values().map(|v| v * v).sum::<f64>().sqrt();
+
+        struct Profile<'a> {
+            indexed: &'a IndexedPost,
+            tags: HashSet<String>,
+            vector: HashMap<&'a str, f64>,
+            norm: f64,
+        }
+        let profiles: Vec<Profile> = self
+            .posts
+            .values()
+            .map(|indexed| {
+                let vector = self.tf_idf(indexed);
+                Profile {
+                    indexed,
+                    tags: indexed
+                        .document
+                        .tags
+                        .iter()
+                        .map(|tag| tag.to_lowercase())
+                        .collect(),
+                    norm: norm(&vector),
+                    vector,
+                }
+            })
+            .collect();
+
+        let mut related = HashMap::new();
+        for current in &profiles {
+            let mut scored: Vec<(&IndexedPost, f64)> = profiles
+                .iter()
+                .filter(|other| other.indexed.post.path != current.indexed.post.path)
+                .map(|other| {
+                    let tag_score = match current.tags.union(&other.tags).count() {
+                        0 => 0.0,
+                        union => {
+                            current.tags.intersection(&other.tags).count() as f64 / union as f64
+                        }
+                    };
+
+                    let dot: f64 = current
+                        .vector
+                        .iter()
+                        .filter_map(|(term, weight)| {
+                            other.vector.get(term).map(|other| weight * other)
+                        })
+                        .sum();
+                    let text_score = match current.norm * other.norm {
+                        norms if norms > 0.0 => dot / norms,
+                        _ => 0.0,
+                    };
+
+                    (other.indexed, RELATED_TAG_WEIGHT * tag_score + text_score)
+                })
+                .filter(|(_, score)| *score >= MIN_RELATED_SCORE)
+                .collect();
+
+            scored.sort_by(|a, b| {
+                b.1.total_cmp(&a.1)
+                    .then_with(|| b.0.post.date().cmp(&a.0.post.date()))
+            });
+            related.insert(
+                current.indexed.post.path.clone(),
+                scored
+                    .into_iter()
+                    .take(limit)
+                    .map(|(indexed, _)| indexed.post.clone())
+                    .collect(),
+            );
+        }
+        self.related = related;
+    }
+
+    /// Term weights of a post, scaled down for terms common to many posts.
+    fn tf_idf<'a>(&self, indexed: &'a IndexedPost) -> HashMap<&'a str, f64> {
+        let total = self.posts.len() as f64;
+        indexed
+            .weights
+            .iter()
+            .map(|(term, weight)| {
+                let idf = self
+                    .terms
+                    .get(term)
+                    .map_or(0.0, |postings| (total / postings.len() as f64).ln());
+                (term.as_str(), weight * idf)
+            })
+            .collect()
+    }
+
+    /// Drops posts that are no longer searchable, e.g. deleted or unpublished,
+    /// returning whether there were any.
+    pub fn retain(&mut self, paths: &HashSet<PathBuf>) -> bool {
+        let removed: Vec<PathBuf> = self
+            .posts
+            .keys()
+            .filter(|path| !paths.contains(*path))
+            .cloned()
+            .collect();
+        for path in &removed {
+            self.remove(path);
+        }
+        !removed.is_empty()
+    }
+
+    /// Posts matching every query word, exactly or as a prefix, best first. Scores
+    /// are TF-IDF weighted by where in the post a word occurs.
+    pub fn search(&self, query: &SearchQuery) -> Vec<SearchHit> {
+        if query.terms.is_empty() {
+            return Vec::new();
+        }
+
+        let total = self.posts.len() as f64;
+        let mut scores: HashMap<&PathBuf, f64> = HashMap::new();
+
+        for (i, query_term) in query.terms.iter().enumerate() {
+            let mut term_scores: HashMap<&PathBuf, f64> = HashMap::new();
+            for (term, postings) in self.terms.range(query_term.clone()..) {
+                if !term.starts_with(query_term.as_str()) {
+                    break;
+                }
+                let idf = (1.0 + total / postings.len() as f64).ln();
+                let factor = if term == query_term {
+                    1.0
+                } else {
+                    PREFIX_FACTOR
+                };
+                for (path, occurrences) in postings {
+                    *term_scores.entry(path).or_default() += occurrences.weight() * idf * factor;
+                }
+            }
+
+            if i == 0 {
+                scores = term_scores;
+            } else {
+                scores.retain(|path, _| term_scores.contains_key(path));
+                for (path, score) in scores.iter_mut() {
+                    *score += term_scores[path];
+                }
+            }
+        }
+
+        let mut hits: Vec<SearchHit> = scores
+            .into_iter()
+            .filter_map(|(path, score)| self.posts.get(path).map(|indexed| (indexed, score)))
+            .filter(|(indexed, _)| {
+                query.tags.iter().all(|tag| {
+                    indexed
+                        .document
+                        .tags
+                        .iter()
+                        .any(|post_tag| post_tag.eq_ignore_ascii_case(tag))
+                })
+            })
+            .map(|(indexed, score)| SearchHit {
+                post: indexed.post.clone(),
+                score,
+                snippet: snippet(&indexed.document.text, &query.terms),
+            })
+            .collect();
+
+        hits.sort_by(|a, b| {
+            b.score
+                .total_cmp(&a.score)
+                .then_with(|| b.post.date().cmp(&a.post.date()))
+        });
+        hits
+    }
+}
+
+/// Content of the search page: a search form and the results of `query`.
+pub fn search_page_html(query: &str, tag: Option<&str>, hits: &[SearchHit]) -> String {
+    let mut html = search_form(query, tag);
+
+    if query.trim().is_empty() {
+        return html;
+    }
+
+    if hits.is_empty() {
+        html.push_str(&format!(
+            "<p class=\"my-4 text-gruvbox-fg-dim\">No posts match <strong>{}</strong>.</p>\n",
+            escape_html(query)
+        ));
+        return html;
+    }
+
+    html.push_str("<ul>\n");
+    for hit in hits {
+        html.push_str(&format!(
+            r#"<li class="mb-6"><a href="{}" class="text-gruvbox-blue hover:text-gruvbox-aqua">{}</a>"#,
+            escape_html(&hit.post.href()),
+            escape_html(hit.post.title())
+        ));
+        if let Some(date) = hit.post.date() {
+            html.push_str(&format!(
+                r#" <span class="text-sm text-gruvbox-gray">{}</span>"#,
+                date.format("%Y-%m-%d")
+            ));
+        }
+        html.push_str(&format!(
+            "\n<p class=\"text-sm text-gruvbox-fg-dim\">{}</p></li>\n",
+            hit.snippet.replace(
+                "<mark>",
+                r#"<mark class="bg-gruvbox-yellow text-gruvbox-bg">"#
+            )
+        ));
+    }
+    html.push_str("</ul>\n");
+
+    html
+}
+
+/// Content of the search page in static exports, where the widget at `widget_url`
+/// searches the index at `index_url` in the browser.
+pub fn static_search_page_html(index_url: &str, widget_url: &str) -> String {
+    format!(
+        r#"<div data-search-index="{}">
+{}<div data-search-results></div>
+</div>
+<script src="{}"></script>
+"#,
+        escape_html(index_url),
+        search_form("", None),
+        escape_html(widget_url)
+    )
+}
+
+/// Compact index of `documents` for the search widget.
+pub fn static_index(documents: &[SearchDocument]) -> StaticSearchIndex {
+    StaticSearchIndex {
+        documents: documents.iter().map(StaticDocument::from).collect(),
+    }
+}
+
+fn search_form(query: &str, tag: Option<&str>) -> String {
+    let mut html = String::from(
+        "<h1 class=\"text-2xl text-gruvbox-yellow font-normal mt-8 mb-6 relative\">Search</h1>\n",
+    );
+
+    html.push_str(&format!(
+        r#"<form action="{}/search" method="get" class="flex space-x-3 mb-8">
+<input type="search" name="q" value="{}" placeholder="Search posts" autofocus class="flex-grow bg-gruvbox-bg border border-gruvbox-gray rounded px-3 py-1 text-gruvbox-fg">
+"#,
+        BLOG_URL_PREFIX,
+        escape_html(query)
+    ));
+    if let Some(tag) = tag {
+        html.push_str(&format!(
+            r#"<input type="hidden" name="tag" value="{}">
+"#,
+            escape_html(tag)
+        ));
+    }
+    html.push_str(
+        r#"<button type="submit" class="text-gruvbox-blue hover:text-gruvbox-aqua">Search</button>
+</form>
+"#,
+    );
+
+    html
+}
+
+/// Lowercase words of a text, as stored in the index.
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Escaped excerpt of `text` around the first word matching a query term, with
+/// matching words wrapped in `<mark>`.
+fn snippet(text: &str, terms: &[String]) -> String {
+    let matches = |word: &str| {
+        let word = word.to_lowercase();
+        terms.iter().any(|term| word.starts_with(term.as_str()))
+    };
+
+    let words: Vec<(usize, &str)> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| (word.as_ptr() as usize - text.as_ptr() as usize, word))
+        .collect();
+    let first = words
+        .iter()
+        .find(|(_, word)| matches(word))
+        .map_or(0, |(start, _)| *start);
+
+    // Start a few words before the first match, at a word boundary
+    let start = words
+        .iter()
+        .map(|(start, _)| *start)
+        .filter(|start| *start + SNIPPET_LENGTH / 4 >= first)
+        .find(|start| *start <= first)
+        .unwrap_or(0);
+    let end = words
+        .iter()
+        .map(|(start, word)| start + word.len())
+        .take_while(|end| *end <= start + SNIPPET_LENGTH)
+        .last()
+        .unwrap_or(text.len());
+
+    let mut html = String::new();
+    if start > 0 {
+        html.push('…');
+    }
+    let mut position = start;
+    for (word_start, word) in words
+        .iter()
+        .filter(|(word_start, _)| *word_start >= start && *word_start < end)
+    {
+        html.push_str(&escape_html(&text[position..*word_start]));
+        if matches(word) {
+            html.push_str(&format!("<mark>{}</mark>", escape_html(word)));
+        } else {
+            html.push_str(&escape_html(word));
+        }
+        position = word_start + word.len();
+    }
+    html.push_str(&escape_html(&text[position..end.max(position)]));
+    if end < text.len() {
+        html.push('…');
+    }
+    html
+}
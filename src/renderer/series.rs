@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+
+use super::{escape_html, posts::slugify, posts::Post, BLOG_URL_PREFIX};
+
+/// Posts sharing a `series` name, in reading order.
+#[derive(Debug, Clone)]
+pub struct Series {
+    pub name: String,
+    /// Series are identified by the slug of their name, so spelling variants match
+    pub slug: String,
+    pub parts: Vec<Post>,
+}
+
+impl Series {
+    /// Groups posts by series, each ordered by `series_order`, then date, then title.
+    pub fn collect(posts: &[Post]) -> Vec<Series> {
+        let mut by_slug: HashMap<String, Series> = HashMap::new();
+        for post in posts {
+            let Some(name) = post.series() else {
+                continue;
+            };
+            let slug = slugify(name);
+            if slug.is_empty() {
+                continue;
+            }
+            by_slug
+                .entry(slug.clone())
+                .or_insert_with(|| Series {
+                    name: name.to_string(),
+                    slug,
+                    parts: Vec::new(),
+                })
+                .parts
+                .push(post.clone());
+        }
+
+        let mut series: Vec<Series> = by_slug.into_values().collect();
+        for series in &mut series {
+            series.parts.sort_by(|a, b| {
+                let order = |post: &Post| (post.series_order().is_none(), post.series_order());
+                order(a)
+                    .cmp(&order(b))
+                    .then_with(|| a.date().cmp(&b.date()))
+                    .then_with(|| a.title().cmp(b.title()))
+            });
+        }
+        series.sort_by(|a, b| a.slug.cmp(&b.slug));
+        series
+    }
+
+    pub fn href(&self) -> String {
+        format!("{}/series/{}", BLOG_URL_PREFIX, self.slug)
+    }
+
+    /// 0-based position of `post` in the series.
+    pub fn position(&self, post: &Post) -> Option<usize> {
+        self.parts.iter().position(|part| part.path == post.path)
+    }
+
+    /// Box listing the parts of the series with `current` highlighted, and links
+    /// to the parts before and after it.
+    pub fn box_html(&self, current: &Post) -> String {
+        let Some(position) = self.position(current) else {
+            return String::new();
+        };
+
+        let mut html = format!(
+            r#"<aside class="border border-gruvbox-gray rounded-md bg-gruvbox-bg px-4 py-2 my-6">
+<p class="text-gruvbox-fg-dim">Part {} of {} in <a href="{}" class="text-gruvbox-blue hover:text-gruvbox-aqua">{}</a></p>
+"#,
+            position + 1,
+            self.parts.len(),
+            escape_html(&self.href()),
+            escape_html(&self.name)
+        );
+        html.push_str(&self.parts_html(Some(position)));
+
+        let previous = position
+            .checked_sub(1)
+            .and_then(|i| self.parts.get(i))
+            .map(|part| {
+                format!(
+                    r#"<a href="{}" class="text-gruvbox-blue hover:text-gruvbox-aqua">← {}</a>"#,
+                    escape_html(&part.href()),
+                    escape_html(part.title())
+                )
+            })
+            .unwrap_or_default();
+        let next = self
+            .parts
+            .get(position + 1)
+            .map(|part| {
+                format!(
+                    r#"<a href="{}" class="text-gruvbox-blue hover:text-gruvbox-aqua">{} →</a>"#,
+                    escape_html(&part.href()),
+                    escape_html(part.title())
+                )
+            })
+            .unwrap_or_default();
+        html.push_str(&format!(
+            "<p class=\"flex justify-between text-sm\"><span>{}</span><span>{}</span></p>\n</aside>\n",
+            previous, next
+        ));
+
+        html
+    }
+
+    /// Content of the series page: its name and the parts in order.
+    pub fn page_html(&self) -> String {
+        format!(
+            "<h1 class=\"text-2xl text-gruvbox-yellow font-normal mt-8 mb-6 relative\">{}</h1>\n<p class=\"my-4 text-gruvbox-fg-dim\">A series in {} parts.</p>\n{}",
+            escape_html(&self.name),
+            self.parts.len(),
+            self.parts_html(None)
+        )
+    }
+
+    fn parts_html(&self, current: Option<usize>) -> String {
+        let mut html = String::from("<ol class=\"my-2\">\n");
+        for (i, part) in self.parts.iter().enumerate() {
+            if Some(i) == current {
+                html.push_str(&format!(
+                    "<li><span class=\"text-gruvbox-yellow font-bold\">{}</span></li>\n",
+                    escape_html(part.title())
+                ));
+                continue;
+            }
+
+            html.push_str(&format!(
+                r#"<li><a href="{}" class="text-gruvbox-blue hover:text-gruvbox-aqua">{}</a>"#,
+                escape_html(&part.href()),
+                escape_html(part.title())
+            ));
+            if let Some(date) = part.date().filter(|_| current.is_none()) {
+                html.push_str(&format!(
+                    r#" <span class="text-sm text-gruvbox-gray">{}</span>"#,
+                    date.format("%Y-%m-%d")
+                ));
+            }
+            html.push_str("</li>\n");
+        }
+        html.push_str("</ol>\n");
+        html
+    }
+}
@@ -0,0 +1,231 @@
+use std::{fs, io::Write, path::Path, process};
+
+use sha2::{Digest, Sha256};
+
+use crate::apierror::ApiError;
+
+use super::{
+    error::RendererError,
+    posts::{is_valid_redirect_source, parse_date, parse_list},
+};
+
+/// Metadata keys whose values must be `true` or `false`
+const BOOLEAN_KEYS: &[&str] = &["draft", "unlisted"];
+
+/// Condition an edit is made under, from `If-Match` / `If-None-Match` headers.
+pub enum Precondition {
+    /// Unconditional
+    None,
+    /// `If-Match: *`, the post must exist
+    Exists,
+    /// `If-Match` with ETags, the post must exist and have one of them
+    Matches(Vec<String>),
+    /// `If-None-Match: *`, the post must not exist yet
+    Absent,
+}
+
+impl Precondition {
+    /// Whether the condition holds for a post with `etag`, or no post for `None`.
+    pub fn holds(&self, etag: Option<&str>) -> bool {
+        match self {
+            Precondition::None => true,
+            Precondition::Exists => etag.is_some(),
+            Precondition::Matches(etags) => {
+                etag.is_some_and(|etag| etags.iter().any(|e| e == etag))
+            }
+            Precondition::Absent => etag.is_none(),
+        }
+    }
+}
+
+/// Content hash of a post's source, used as its ETag for conditional edits.
+pub fn source_etag(source: &str) -> String {
+    let digest = Sha256::digest(source.as_bytes());
+    digest[..16].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Splits a post into its leading `<!-- key: value -->` block and the markdown after it.
+pub fn split_metadata(source: &str) -> (Vec<(String, String)>, &str) {
+    let trimmed = source.trim_start();
+    let Some(block) = trimmed.strip_prefix("<!--") else {
+        return (Vec::new(), source);
+    };
+    let Some(end) = block.find("-->") else {
+        return (Vec::new(), source);
+    };
+
+    let metadata = block[..end]
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key.trim().to_lowercase(), value.trim().to_string()))
+        .filter(|(key, _)| !key.is_empty())
+        .collect();
+    let body = block[end + 3..].trim_start_matches(['\r', '\n']);
+
+    (metadata, body)
+}
+
+/// Inverse of `split_metadata`.
+pub fn join_metadata(metadata: &[(String, String)], body: &str) -> String {
+    if metadata.is_empty() {
+        return body.to_string();
+    }
+
+    let mut source = String::from("<!--\n");
+    for (key, value) in metadata {
+        source.push_str(&format!("{}: {}\n", key, value));
+    }
+    source.push_str("-->\n\n");
+    source.push_str(body);
+    source
+}
+
+/// Checks metadata submitted for a post: a title is required, keys are lowercase
+/// words, values fit on one line and typed keys parse.
+pub fn validate_metadata(metadata: &[(String, String)]) -> Result<(), ApiError> {
+    if !metadata
+        .iter()
+        .any(|(key, value)| key == "title" && !value.is_empty())
+    {
+        return Err(RendererError::MissingMetadataError("title".to_string()).into());
+    }
+
+    for (key, value) in metadata {
+        if key.is_empty()
+            || !key
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+        {
+            return Err(ApiError::validation_error(format!(
+                "invalid metadata key {:?}",
+                key
+            )));
+        }
+        if value.contains(['\n', '\r']) || value.contains("-->") {
+            return Err(ApiError::validation_error(format!(
+                "{} must be a single line",
+                key
+            )));
+        }
+        if key == "date" && parse_date(value).is_none() {
+            return Err(ApiError::validation_error(format!(
+                "date {:?} is not a valid date",
+                value
+            )));
+        }
+        if key == "series_order" && value.parse::<i64>().is_err() {
+            return Err(ApiError::validation_error(format!(
+                "series_order {:?} is not a whole number",
+                value
+            )));
+        }
+        if key == "aliases" {
+            if let Some(alias) = parse_list(value)
+                .into_iter()
+                .find(|alias| !is_valid_redirect_source(alias))
+            {
+                return Err(ApiError::validation_error(format!(
+                    "alias {:?} must be a path inside the blog without . or .. segments",
+                    alias
+                )));
+            }
+        }
+        if BOOLEAN_KEYS.contains(&key.as_str()) && !matches!(value.as_str(), "true" | "false") {
+            return Err(ApiError::validation_error(format!(
+                "{} must be true or false",
+                key
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks a URL path for a new post: lowercase words separated by dashes or
+/// underscores, in optional subdirectories.
+pub fn validate_slug(slug: &str) -> Result<(), ApiError> {
+    let valid = !slug.is_empty()
+        && slug.split('/').all(|segment| {
+            segment
+                .chars()
+                .next()
+                .is_some_and(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+                && segment
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+        });
+
+    if valid {
+        Ok(())
+    } else {
+        Err(ApiError::validation_error(format!(
+            "invalid slug {:?}: use lowercase letters, digits, dashes and underscores",
+            slug
+        )))
+    }
+}
+
+/// Writes a file by writing a hidden sibling and renaming it over the target, so
+/// readers never see a partially written post.
+pub fn write_atomic(path: &Path, content: &str) -> Result<(), RendererError> {
+    let write_error = |source| RendererError::FileWriteError {
+        path: path.to_string_lossy().to_string(),
+        source,
+    };
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(write_error)?;
+    }
+
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp = path.with_file_name(format!(".{}.{}.tmp", file_name, process::id()));
+
+    let result = fs::File::create(&temp)
+        .and_then(|mut file| {
+            file.write_all(content.as_bytes())?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&temp, path));
+
+    if let Err(e) = result {
+        let _ = fs::remove_file(&temp);
+        return Err(write_error(e));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(fields: &[(&str, &str)]) -> Vec<(String, String)> {
+        fields
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn aliases_must_stay_inside_the_blog() {
+        assert!(
+            validate_metadata(&metadata(&[("title", "T"), ("aliases", "/old, old/post")])).is_ok()
+        );
+
+        for aliases in ["../../x", "old, ../x", "[a/../../b]", "a//b"] {
+            let result = validate_metadata(&metadata(&[("title", "T"), ("aliases", aliases)]));
+            assert!(result.is_err(), "{}", aliases);
+        }
+    }
+
+    #[test]
+    fn metadata_keys_are_read_lowercase() {
+        let (fields, body) = split_metadata("<!--\nTitle: Post\n Date : 2024-01-02\n-->\n\nBody");
+        assert_eq!(
+            fields,
+            metadata(&[("title", "Post"), ("date", "2024-01-02")])
+        );
+        assert_eq!(body, "Body");
+        assert!(validate_metadata(&fields).is_ok());
+    }
+}